@@ -4,10 +4,12 @@
 
 use std::error::Error;
 use std::fmt::{Debug, Display, Formatter};
+use std::fs::File;
+use std::io::{BufWriter, Write};
 use std::path::{Path, PathBuf};
 use metaphor_apk_rs::read::ApkReader;
 use metaphor_apk_rs::serial::CompressionType;
-use metaphor_apk_rs::write::ApkWriter;
+use metaphor_apk_rs::write::{validate_name, ApkWriter, CompressionPolicy};
 
 #[derive(Debug)]
 pub enum AppError {
@@ -18,6 +20,13 @@ pub enum AppError {
     UnknownCompressionType(String)
 }
 
+/// Compression picked on the command line
+enum CompressionArg {
+    Fixed(CompressionType),
+    // whichever of zlib and LZ4 is smallest, along with zstd when `zstd` is set
+    Smallest { zstd: bool }
+}
+
 impl Error for AppError {}
 impl Display for AppError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
@@ -32,8 +41,10 @@ Output (optional): Where to save the extracted folder to.\n\
 DDS Folder Mode: ./metaphor-apk-pack [input folder] (compression) (output)\n\
 Input: A path to a folder containing one or more DDS files and FileList.txt\n\
 Compression (optional): Define the compression algorithm used.\n\
-(Valid options are Zlib, LZ4 and ZStd. LZ4 is used by default)\n\
-Note that ZStd can only be used if your mod has a dependency set with OpenGFD\n\
+(Valid options are Zlib, LZ4, ZStd, Smallest and Smallest-ZStd. LZ4 is used by default)\n\
+Smallest picks whichever of Zlib and LZ4 gives the smallest output for each file,\n\
+and Smallest-ZStd also tries ZStd\n\
+Note that ZStd and Smallest-ZStd can only be used if your mod has a dependency set with OpenGFD\n\
 Output (optional): A path to the folder where the output APK will be created")
             },
             _ => <Self as Debug>::fmt(self, f)
@@ -51,7 +62,7 @@ fn app() -> Result<(), Box<dyn Error>> {
     // handle CLI args
     let args: Vec<String> = std::env::args().enumerate()
         .filter_map(|(i, a)| if i > 0 { Some(a) } else { None }).collect();
-    if args.len() < 1 {
+    if args.is_empty() {
         return Err(Box::new(AppError::PrintUsage));
     }
     let path = Path::new(&args[0]);
@@ -62,7 +73,7 @@ fn app() -> Result<(), Box<dyn Error>> {
     let out_idx = if meta.is_file() { 1 } else { 2 };
     let output = match args.len() > out_idx {
         true => PathBuf::from(&args[out_idx]),
-        false => PathBuf::from(path.parent().unwrap().join(path.file_stem().unwrap()))
+        false => path.parent().unwrap().join(path.file_stem().unwrap())
     };
     if meta.is_file() {
         // APK mode
@@ -73,24 +84,47 @@ fn app() -> Result<(), Box<dyn Error>> {
         if !std::fs::exists(&output)? {
             std::fs::create_dir(&output)?;
         }
-        for (name, bytes) in apk.get_all_files()? {
-            println!("Write to {:?}: {} bytes", output.join(name), bytes.len());
-            std::fs::write(output.join(name), bytes.as_slice())?;
+        let entries: Vec<_> = apk.entries().collect();
+        let mut file_list = String::new();
+        for (i, entry) in entries.into_iter().enumerate() {
+            // names come from the archive, so entries that can't be read or whose names could
+            // lead outside of the output folder are reported and skipped
+            let entry = match entry.and_then(|e| { validate_name(&e.name)?; Ok(e) }) {
+                Ok(e) => e,
+                Err(e) => {
+                    println!("Skipping entry {} ({:?}): {}", i, apk.file_header(i).unwrap().name_lossy(), e);
+                    continue;
+                }
+            };
+            let path = output.join(&entry.name);
+            println!("Write to {:?}: {} bytes", path, entry.decompressed_size);
+            let mut file = BufWriter::new(File::create(&path)?);
+            if let Err(e) = apk.extract_to_by_index(entry.index, &mut file) {
+                println!("Skipping entry {} ({:?}): {}", i, entry.name, e);
+                drop(file);
+                std::fs::remove_file(&path)?;
+                continue;
+            }
+            file.flush()?;
+            file_list.push_str(&entry.name);
+            file_list.push('\n');
         }
-        std::fs::write(output.join("FileList.txt"), apk.create_file_list())?;
+        std::fs::write(output.join("FileList.txt"), file_list)?;
     } else {
         // DDS folder mode
         let compression = match args.len() > 1 {
             true => {
-                let cmp_str = (&args[1]).to_lowercase();
+                let cmp_str = args[1].to_lowercase();
                 match cmp_str.as_ref() {
-                    "zlib" => CompressionType::ZLib,
-                    "lz4" => CompressionType::LZ4,
-                    "zstd" => CompressionType::ZStandard,
+                    "zlib" => CompressionArg::Fixed(CompressionType::ZLib),
+                    "lz4" => CompressionArg::Fixed(CompressionType::LZ4),
+                    "zstd" => CompressionArg::Fixed(CompressionType::ZStandard),
+                    "smallest" => CompressionArg::Smallest { zstd: false },
+                    "smallest-zstd" => CompressionArg::Smallest { zstd: true },
                     _ => return Err(Box::new(AppError::UnknownCompressionType(cmp_str)))
                 }
             },
-            false => CompressionType::LZ4
+            false => CompressionArg::Fixed(CompressionType::LZ4)
         };
         let file_list = path.join("FileList.txt");
         if !std::fs::exists(&file_list)? {
@@ -98,16 +132,27 @@ fn app() -> Result<(), Box<dyn Error>> {
         }
 
         let out_path = match output.extension() {
-            Some(_) => PathBuf::from(output),
+            Some(_) => output,
             None => output.join(format!("{}.apk", path.file_stem().unwrap().to_str().unwrap()))
         };
         println!("Saving to \"{}\"", out_path.to_str().unwrap());
         let mut apk = ApkWriter::setup(out_path)?;
+        match compression {
+            CompressionArg::Fixed(compression) => apk.set_default_compression(compression),
+            CompressionArg::Smallest { zstd } => {
+                apk.set_compression_policy(CompressionPolicy::smallest(
+                    &[CompressionType::ZLib, CompressionType::LZ4, CompressionType::ZStandard]));
+                // zstd archives only load with OpenGFD, so it has to be asked for
+                apk.set_exclude_zstd(!zstd);
+            }
+        }
         let file_list = std::fs::read_to_string(&file_list)?;
         for entry in file_list.lines() {
-            apk.add_external_file_with_compression(compression, &path.join(entry))?;
+            apk.add_external_file(path.join(entry))?;
+        }
+        for entry in apk.save()? {
+            println!("{}: {:?}, {} -> {} bytes", entry.name, entry.compression, entry.decompressed_size, entry.stored_size);
         }
-        apk.save()?;
     }
     Ok(())
 }
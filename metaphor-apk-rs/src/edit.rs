@@ -0,0 +1,248 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, Write};
+use std::path::Path;
+use crate::read::{ApkReader, ReaderError};
+use crate::write::{validate_name, ApkWriter, ApkWriterEntry, AtomicFile, CompressionOptions, SavedEntry, WriterError};
+
+enum EditorEntry {
+    // index into the archive being edited, copied across without decompressing
+    Original(usize),
+    // compressed with the default compression when not set
+    Data(Option<CompressionOptions>, Vec<u8>)
+}
+
+/// Edits an existing APK. Entries can be replaced, inserted, removed and renamed, and saving
+/// writes a new archive through [`ApkWriter`] that copies every untouched entry as is.
+pub struct ApkEditor<S: Read + Seek> {
+    reader: ApkReader<S>,
+    entries: Vec<(String, EditorEntry)>,
+    compression: CompressionOptions
+}
+
+impl ApkEditor<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        Self::new(ApkReader::read(path)?)
+    }
+}
+
+impl<S: Read + Seek> ApkEditor<S> {
+    pub fn new(reader: ApkReader<S>) -> Result<Self, Box<dyn Error>> {
+        let entries = reader.files.iter().enumerate().map(|(i, f)| {
+            let name = f.name().map_err(|_| ReaderError::InvalidName { index: i, name: f.name_lossy().into_owned() })?;
+            Ok((name.to_string(), EditorEntry::Original(i)))
+        }).collect::<Result<Vec<_>, ReaderError>>()?;
+        Ok(Self { reader, entries, compression: CompressionOptions::default() })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Entry names in the order they'll be saved.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(|(name, _)| name.as_str())
+    }
+
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        self.entries.iter().position(|(n, _)| n == name)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.index_of(name).is_some()
+    }
+
+    fn find(&self, name: &str) -> Result<usize, ReaderError> {
+        self.index_of(name).ok_or_else(|| ReaderError::FileNotFound(name.to_string()))
+    }
+
+    fn check_new_name(&self, name: &str) -> Result<(), WriterError> {
+        validate_name(name)?;
+        if self.contains(name) {
+            return Err(WriterError::FileAlreadyExists(name.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Replace the contents of an entry, compressing it with the same codec as the original.
+    pub fn replace(&mut self, name: &str, data: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        let index = self.find(name)?;
+        let compression = match &self.entries[index].1 {
+            EditorEntry::Original(i) => {
+                let f = &self.reader.files[*i];
+                Some(ApkReader::read_data_header(&mut self.reader.owner, self.reader.base, f)?.get_compress_type().into())
+            },
+            EditorEntry::Data(compression, _) => *compression
+        };
+        self.entries[index].1 = EditorEntry::Data(compression, data);
+        Ok(())
+    }
+
+    pub fn replace_with_compression(&mut self, name: &str, compression: impl Into<CompressionOptions>,
+        data: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        let index = self.find(name)?;
+        self.entries[index].1 = EditorEntry::Data(Some(compression.into()), data);
+        Ok(())
+    }
+
+    pub fn insert_at(&mut self, index: usize, name: &str, data: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        self.insert_at_inner(index, name, None, data)
+    }
+
+    pub fn insert_at_with_compression(&mut self, index: usize, name: &str,
+        compression: impl Into<CompressionOptions>, data: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        self.insert_at_inner(index, name, Some(compression.into()), data)
+    }
+
+    fn insert_at_inner(&mut self, index: usize, name: &str, compression: Option<CompressionOptions>,
+        data: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        if index > self.entries.len() {
+            return Err(Box::new(ReaderError::IndexOutOfBounds(index)));
+        }
+        self.check_new_name(name)?;
+        self.entries.insert(index, (name.to_string(), EditorEntry::Data(compression, data)));
+        Ok(())
+    }
+
+    /// Compression used by inserted entries that weren't given their own. Defaults to LZ4.
+    pub fn set_default_compression(&mut self, compression: impl Into<CompressionOptions>) {
+        self.compression = compression.into();
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+        let index = self.find(name)?;
+        self.entries.remove(index);
+        Ok(())
+    }
+
+    pub fn rename(&mut self, old: &str, new: &str) -> Result<(), Box<dyn Error>> {
+        let index = self.find(old)?;
+        if old != new {
+            self.check_new_name(new)?;
+        }
+        self.entries[index].0 = new.to_string();
+        Ok(())
+    }
+
+    /// Write the edited archive to `sink`, keeping the original archive header.
+    pub fn save_to<W: Write + Seek>(&mut self, sink: &mut W) -> Result<Vec<SavedEntry>, Box<dyn Error>> {
+        let mut writer = ApkWriter::new(sink);
+        *writer.header_mut() = self.reader.header().clone();
+        writer.set_default_compression(self.compression);
+        // names were checked as they were added to the editor, and any duplicate names from the
+        // original archive are kept as they are
+        for (name, entry) in &self.entries {
+            let entry = match entry {
+                EditorEntry::Original(i) => {
+                    let mut entry = ApkWriterEntry::raw(self.reader.get_raw_entry_by_index(*i)?);
+                    let f = &self.reader.files[*i];
+                    entry.set_file_header_unk(f.get_unk(), f.get_unk2());
+                    entry
+                },
+                EditorEntry::Data(compression, data) => {
+                    let mut entry = ApkWriterEntry::new(self.compression, Box::new(data.as_slice()));
+                    entry.set_compression(*compression);
+                    entry
+                }
+            };
+            writer.push_entry(name.clone(), entry);
+        }
+        writer.save()
+    }
+
+    /// Write the edited archive to `path`. The archive is written to a temporary file that only
+    /// replaces `path` once it's complete, so `path` can be the file that's being edited.
+    pub fn save<P: AsRef<Path>>(&mut self, path: P) -> Result<Vec<SavedEntry>, Box<dyn Error>> {
+        let mut sink = AtomicFile::create(path)?;
+        let saved = self.save_to(&mut sink)?;
+        sink.commit()?;
+        Ok(saved)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::error::Error;
+    use std::io::Cursor;
+    use crate::edit::ApkEditor;
+    use crate::read::ApkReader;
+    use crate::read::tests::build_apk;
+    use crate::write::WriterError;
+
+    #[test]
+    fn test_edit() -> Result<(), Box<dyn Error>> {
+        let original = build_apk(&[("a.dds", b"first"), ("b.dds", b"second"), ("c.dds", b"third")]);
+        let mut editor = ApkEditor::new(ApkReader::new(Cursor::new(original.clone()))?)?;
+        editor.replace("a.dds", b"replaced".to_vec())?;
+        editor.insert_at(1, "d.dds", b"inserted".to_vec())?;
+        editor.remove("b.dds")?;
+        editor.rename("c.dds", "e.dds")?;
+        let err = editor.rename("a.dds", "d.dds").err().unwrap();
+        assert!(matches!(err.downcast_ref::<WriterError>(), Some(WriterError::FileAlreadyExists(_))));
+        assert!(editor.remove("b.dds").is_err());
+        assert_eq!(editor.names().collect::<Vec<_>>(), ["a.dds", "d.dds", "e.dds"]);
+
+        let mut sink = Cursor::new(vec![]);
+        editor.save_to(&mut sink)?;
+        let mut edited = ApkReader::new(Cursor::new(sink.into_inner()))?;
+        assert_eq!(edited.get_all_files()?, [
+            ("a.dds".to_string(), b"replaced".to_vec()),
+            ("d.dds".to_string(), b"inserted".to_vec()),
+            ("e.dds".to_string(), b"third".to_vec())
+        ]);
+        // renamed entry is copied without being recompressed
+        let mut original = ApkReader::new(Cursor::new(original))?;
+        assert_eq!(edited.get_raw_entry("e.dds")?.as_bytes(), original.get_raw_entry("c.dds")?.as_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn test_edit_unchanged() -> Result<(), Box<dyn Error>> {
+        let original = build_apk(&[("a.dds", b"first"), ("b.dds", b"second")]);
+        let mut editor = ApkEditor::new(ApkReader::new(Cursor::new(original.clone()))?)?;
+        let mut sink = Cursor::new(vec![]);
+        editor.save_to(&mut sink)?;
+        assert_eq!(sink.into_inner(), original);
+        Ok(())
+    }
+
+    #[test]
+    fn test_edit_duplicate_names() -> Result<(), Box<dyn Error>> {
+        let original = build_apk(&[("a.dds", b"first"), ("a.dds", b"second"), ("b.dds", b"third")]);
+        let mut editor = ApkEditor::new(ApkReader::new(Cursor::new(original.clone()))?)?;
+        let mut sink = Cursor::new(vec![]);
+        editor.save_to(&mut sink)?;
+        assert_eq!(sink.into_inner(), original);
+
+        editor.replace("b.dds", b"replaced".to_vec())?;
+        let mut sink = Cursor::new(vec![]);
+        editor.save_to(&mut sink)?;
+        let mut apk = ApkReader::new(Cursor::new(sink.into_inner()))?;
+        assert_eq!(apk.get_file_by_index(0)?, b"first");
+        assert_eq!(apk.get_file_by_index(1)?, b"second");
+        assert_eq!(apk.get_file("b.dds")?, b"replaced");
+        Ok(())
+    }
+
+    #[test]
+    fn test_edit_in_place() -> Result<(), Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!("metaphor-apk-edit-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("edit.apk");
+        std::fs::write(&path, build_apk(&[("a.dds", b"first"), ("b.dds", b"second")]))?;
+        let mut editor = ApkEditor::open(&path)?;
+        editor.replace("b.dds", b"replaced".to_vec())?;
+        editor.save(&path)?;
+        assert_eq!(ApkReader::read(&path)?.get_all_files()?, [
+            ("a.dds".to_string(), b"first".to_vec()),
+            ("b.dds".to_string(), b"replaced".to_vec())
+        ]);
+        assert_eq!(std::fs::read_dir(&dir)?.count(), 1);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
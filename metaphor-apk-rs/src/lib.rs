@@ -1,3 +1,6 @@
+pub mod edit;
+#[cfg(feature = "mmap")]
+pub mod mmap;
 pub mod read;
 pub mod serial;
 pub mod write;
@@ -0,0 +1,117 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::Cursor;
+use std::path::Path;
+use memmap2::Mmap;
+use crate::read::{decompress_block, ApkReader, ReaderError};
+use crate::serial::{DataBlock, DataHeader};
+
+/// Reads an APK that's been mapped into memory. Compressed data is borrowed straight from the
+/// mapping, and data headers are parsed once when the archive is opened, so scanning or
+/// previewing entries doesn't copy anything until it's decompressed.
+pub struct ApkMmapReader {
+    inner: ApkReader<Cursor<Mmap>>,
+    data_headers: Vec<DataHeader>,
+    blocks: Vec<Vec<DataBlock>>
+}
+
+impl ApkMmapReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let file = File::open(path)?;
+        // SAFETY: the mapping is only read from. As with any memory mapped file, modifying the
+        // APK on disk while it's open is not supported.
+        let map = unsafe { Mmap::map(&file)? };
+        let mut inner = ApkReader::new(Cursor::new(map))?;
+        let mut data_headers = Vec::with_capacity(inner.files.len());
+        let mut blocks = Vec::with_capacity(inner.files.len());
+        for f in &inner.files {
+            let data_header = ApkReader::read_data_header(&mut inner.owner, inner.base, f)?;
+            blocks.push(ApkReader::read_blocks(&mut inner.owner, inner.base, f, &data_header)?);
+            data_headers.push(data_header);
+        }
+        Ok(Self { inner, data_headers, blocks })
+    }
+
+    pub fn len(&self) -> usize {
+        self.data_headers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data_headers.is_empty()
+    }
+
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        self.inner.index_of(name)
+    }
+
+    pub fn name(&self, index: usize) -> Option<&str> {
+        self.inner.files.get(index).and_then(|f| f.name().ok())
+    }
+
+    pub fn data_header(&self, index: usize) -> Option<&DataHeader> {
+        self.data_headers.get(index)
+    }
+
+    pub fn blocks(&self, index: usize) -> Option<&[DataBlock]> {
+        self.blocks.get(index).map(|b| b.as_slice())
+    }
+
+    /// Compressed stream for one block of an entry, borrowed from the mapped file.
+    pub fn compressed_block(&self, index: usize, block: usize) -> Option<&[u8]> {
+        let block = self.blocks.get(index)?.get(block)?;
+        let start = (self.inner.base + self.inner.files[index].offset as u64) as usize + block.offset as usize;
+        // bounds were checked against the file table when opening
+        Some(&self.inner.owner.get_ref()[start..start + block.compressed as usize])
+    }
+
+    /// Compressed stream for an entry, borrowed from the mapped file. For entries with more than
+    /// one block, this is the first block.
+    pub fn compressed(&self, index: usize) -> Option<&[u8]> {
+        self.compressed_block(index, 0)
+    }
+
+    /// Decompress an entry into `out`, which must be exactly
+    /// [`DataHeader::get_decompressed_size`] bytes long.
+    pub fn decompress_into(&self, index: usize, out: &mut [u8]) -> Result<(), Box<dyn Error>> {
+        let data_header = self.data_headers.get(index).ok_or(ReaderError::IndexOutOfBounds(index))?;
+        if out.len() != data_header.decompressed as usize {
+            return Err(Box::new(ReaderError::OutputSizeMismatch {
+                expected: data_header.decompressed as usize, actual: out.len() }));
+        }
+        for i in 0..self.blocks[index].len() {
+            let data = self.compressed_block(index, i).unwrap();
+            decompress_block(data_header.compress_type, data, &mut out[data_header.block_range(i)])?;
+        }
+        Ok(())
+    }
+
+    /// Access the underlying reader, for lookups and streaming extraction.
+    pub fn reader(&mut self) -> &mut ApkReader<Cursor<Mmap>> {
+        &mut self.inner
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::error::Error;
+    use crate::mmap::ApkMmapReader;
+    use crate::read::tests::build_apk;
+
+    #[test]
+    fn test_mmap_read() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("metaphor-apk-mmap-{}.apk", std::process::id()));
+        std::fs::write(&path, build_apk(&[("a.dds", b"first"), ("b.dds", &[7; 0x200])]))?;
+        let apk = ApkMmapReader::open(&path)?;
+        let index = apk.index_of("b.dds").unwrap();
+        assert_eq!(apk.name(index), Some("b.dds"));
+        let header = apk.data_header(index).unwrap();
+        assert_eq!(apk.compressed(index).unwrap().len(), header.get_compressed_size() as usize);
+        let mut out = vec![0; header.get_decompressed_size() as usize];
+        apk.decompress_into(index, &mut out)?;
+        assert_eq!(out, [7; 0x200]);
+        assert!(apk.decompress_into(index, &mut [0; 4]).is_err());
+        drop(apk);
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
@@ -1,16 +1,31 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::{Debug, Display, Formatter};
 use std::fs::File;
-use std::io::{BufReader, Read, Seek, SeekFrom};
-use std::mem::MaybeUninit;
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom, Take, Write};
 use std::path::Path;
-use crate::serial::{CompressionType, DataHeader, FileHeader, Header};
+use crate::serial::{CompressionType, DataBlock, DataHeader, FileHeader, Header, APK_DATA_MAGIC, APK_VERSION};
 
 #[derive(Debug)]
 pub enum ReaderError {
     FileNotFound(String),
-    ZStdError(usize)
+    ZStdError(usize),
+    ZLibError(std::io::Error),
+    LZ4Error(String),
+    BadMagic,
+    BadDataMagic { entry: String },
+    UnsupportedVersion(u16),
+    FileTableOutOfBounds { count: u32 },
+    EntryOutOfBounds { entry: String },
+    EntryOverlap { entry: String, other: String },
+    BadDataHeader { entry: String },
+    UnknownCompressionType { entry: String, value: u16 },
+    InvalidName { index: usize, name: String },
+    IndexOutOfBounds(usize),
+    OutputSizeMismatch { expected: usize, actual: usize },
+    DecompressedSizeMismatch { expected: usize, actual: usize },
+    BlockCountMismatch { expected: usize, actual: usize }
 }
 
 impl Error for ReaderError {}
@@ -20,101 +35,735 @@ impl Display for ReaderError {
     }
 }
 
+/// Name and decompressed contents of each entry, in archive order.
+pub type ExtractedFiles = Vec<(String, Vec<u8>)>;
+
+/// Metadata for a single entry, read from the file table and data header without decompressing.
+#[derive(Debug, Clone)]
+pub struct EntryInfo {
+    pub name: String,
+    pub index: usize,
+    pub offset: u32,
+    /// Space reserved for the entry in the archive, including the data header and padding
+    pub stored_size: u32,
+    pub compressed_size: u32,
+    pub decompressed_size: u32,
+    pub compression: CompressionType,
+    pub num_blocks: u16
+}
+
+pub struct Entries<'a, S: Read + Seek> {
+    reader: &'a mut ApkReader<S>,
+    index: usize
+}
+
+impl<S: Read + Seek> Iterator for Entries<'_, S> {
+    type Item = Result<EntryInfo, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let index = self.index;
+        let f = self.reader.files.get(index)?;
+        self.index += 1;
+        Some(ApkReader::entry_info(&mut self.reader.owner, self.reader.base, index, f))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.reader.files.len() - self.index;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<S: Read + Seek> ExactSizeIterator for Entries<'_, S> {}
+
+pub struct Files<'a, S: Read + Seek> {
+    reader: &'a mut ApkReader<S>,
+    index: usize
+}
+
+impl<S: Read + Seek> Iterator for Files<'_, S> {
+    type Item = Result<(EntryInfo, Vec<u8>), Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let index = self.index;
+        let f = self.reader.files.get(index)?;
+        self.index += 1;
+        Some(ApkReader::read_file_with_info(&mut self.reader.owner, self.reader.base, index, f))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.reader.files.len() - self.index;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<S: Read + Seek> ExactSizeIterator for Files<'_, S> {}
+
+/// Decompresses an entry as it's read. Single block zlib and zstd entries are streamed straight
+/// from the archive, while everything else is decompressed one block at a time.
+enum EntryStream<'a, S: Read + Seek> {
+    ZLib(flate2::read::ZlibDecoder<Take<&'a mut S>>),
+    ZStandard(zstd::stream::read::Decoder<'static, BufReader<Take<&'a mut S>>>),
+    Blocks(BlockStream<'a, S>)
+}
+
+impl<S: Read + Seek> Read for EntryStream<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::ZLib(d) => d.read(buf),
+            Self::ZStandard(d) => d.read(buf),
+            Self::Blocks(d) => d.read(buf)
+        }
+    }
+}
+
+struct BlockStream<'a, S: Read + Seek> {
+    owner: &'a mut S,
+    offset: u64,
+    data_header: DataHeader,
+    blocks: Vec<DataBlock>,
+    next: usize,
+    compressed: Vec<u8>,
+    current: Cursor<Vec<u8>>
+}
+
+impl<S: Read + Seek> BlockStream<'_, S> {
+    fn next_block(&mut self) -> std::io::Result<()> {
+        let block = self.blocks[self.next];
+        self.owner.seek(SeekFrom::Start(self.offset + block.offset as u64))?;
+        self.compressed.resize(block.compressed as usize, 0);
+        self.owner.read_exact(&mut self.compressed)?;
+        let size = self.data_header.block_range(self.next).len();
+        let mut out = std::mem::take(self.current.get_mut());
+        out.clear();
+        decompress_block_to(self.data_header.compress_type, &self.compressed, size, &mut out)
+            .map_err(std::io::Error::other)?;
+        self.current = Cursor::new(out);
+        self.next += 1;
+        Ok(())
+    }
+}
+
+impl<S: Read + Seek> Read for BlockStream<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let read = self.current.read(buf)?;
+            if read > 0 || buf.is_empty() || self.next == self.blocks.len() {
+                return Ok(read);
+            }
+            self.next_block()?;
+        }
+    }
+}
+
+/// Stops an entry's stream at its decompressed size, and fails if the stream ends before that.
+struct ExactStream<R: Read> {
+    inner: R,
+    expected: u64,
+    read: u64
+}
+
+impl<R: Read> Read for ExactStream<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = self.expected - self.read;
+        if remaining == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+        let len = remaining.min(buf.len() as u64) as usize;
+        let read = self.inner.read(&mut buf[..len])?;
+        if read == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, ReaderError::DecompressedSizeMismatch {
+                expected: self.expected as usize, actual: self.read as usize }));
+        }
+        self.read += read as u64;
+        Ok(read)
+    }
+}
+
+/// An entry exactly as it's stored in an archive, from the start of its data header through to
+/// the end of its padding. Raw entries can be copied into another archive with
+/// [`crate::write::ApkWriter::add_raw_entry`] without decompressing them.
+#[derive(Debug, Clone)]
+pub struct RawEntry {
+    pub(crate) data_header: DataHeader,
+    pub(crate) blocks: Vec<DataBlock>,
+    pub(crate) data: Vec<u8>
+}
+
+impl RawEntry {
+    /// Build an entry from blocks that were already compressed with the header's compression type.
+    /// The header's compression type, decompressed size and unknown fields are kept, while the
+    /// rest of the header is filled in for the given block.
+    ///
+    /// Only single block entries can be built, since the layout of the block table is inferred
+    /// (see [`DataBlock`]). Multi-block entries can still be copied as is from another archive.
+    pub fn new(data_header: &DataHeader, compressed: &[&[u8]]) -> Result<Self, Box<dyn Error>> {
+        if data_header.block_count() != 1 {
+            return Err(Box::new(ReaderError::BlockCountMismatch { expected: 1, actual: data_header.block_count() }));
+        }
+        Self::with_blocks(data_header, compressed)
+    }
+
+    /// Same as [`RawEntry::new`], but lays out a block table for any number of blocks.
+    pub(crate) fn with_blocks(data_header: &DataHeader, compressed: &[&[u8]]) -> Result<Self, Box<dyn Error>> {
+        if compressed.len() != data_header.block_count() {
+            return Err(Box::new(ReaderError::BlockCountMismatch {
+                expected: data_header.block_count(), actual: compressed.len() }));
+        }
+        let sizes: Vec<usize> = compressed.iter().map(|c| c.len()).collect();
+        let (mut header, blocks) = DataHeader::new_blocks(data_header.compress_type,
+            data_header.decompressed as usize, data_header.get_size_limit() as usize, &sizes)?;
+        header.set_unk(data_header.get_unk());
+        let mut data = vec![];
+        header.write_blocks_to(&blocks, &mut data)?;
+        compressed.iter().for_each(|c| data.extend_from_slice(c));
+        data.resize((data.len() + 0xf) & !0xf, 0); // align to nearest 0x10
+        Ok(Self { data_header: header, blocks, data })
+    }
+
+    pub fn data_header(&self) -> &DataHeader {
+        &self.data_header
+    }
+
+    pub fn blocks(&self) -> &[DataBlock] {
+        &self.blocks
+    }
+
+    /// Compressed bytes of a single block.
+    pub fn compressed_block(&self, block: usize) -> Option<&[u8]> {
+        let block = self.blocks.get(block)?;
+        Some(&self.data[block.offset as usize..(block.offset + block.compressed) as usize])
+    }
+
+    /// Every stored byte, starting with the data header.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn decompress(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut output = vec![];
+        for (i, block) in self.blocks.iter().enumerate() {
+            let compressed = &self.data[block.offset as usize..(block.offset + block.compressed) as usize];
+            decompress_block_to(self.data_header.compress_type, compressed,
+                self.data_header.block_range(i).len(), &mut output)?;
+        }
+        Ok(output)
+    }
+}
+
 pub struct ApkReader<S: Read + Seek> {
-    owner: S,
-    files: Vec<FileHeader>
+    pub(crate) owner: S,
+    // position of the archive in owner, which every offset in the archive is relative to
+    pub(crate) base: u64,
+    header: Header,
+    pub(crate) files: Vec<FileHeader>,
+    // name -> index into files, built once so lookups don't scan the file table
+    lookup: HashMap<Box<[u8]>, usize>,
+    case_insensitive: bool
 }
 
 impl ApkReader<BufReader<File>> {
     pub fn read<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
-        let mut owner = BufReader::new(File::open(path)?);
-        let mut header: MaybeUninit<Header> = MaybeUninit::uninit();
-        owner.read_exact(unsafe { &mut *(header.as_mut_ptr() as *mut [u8; size_of::<Header>()]) })?;
-        let header = unsafe { header.assume_init() };
-        let mut files = Vec::with_capacity(header.count as usize);
-        let head_area = unsafe { std::slice::from_raw_parts_mut(
-            files.as_mut_ptr() as *mut u8, header.count as usize * size_of::<FileHeader>()) };
-        owner.read_exact(head_area)?;
-        unsafe { files.set_len(header.count as usize) };
-        Ok(Self { owner, files })
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<'a> ApkReader<Cursor<&'a [u8]>> {
+    /// Read an APK that's already in memory, borrowing it for as long as the reader is used.
+    pub fn from_bytes(bytes: &'a [u8]) -> Result<Self, Box<dyn Error>> {
+        Self::new(Cursor::new(bytes))
     }
 }
 
 impl<S: Read + Seek> ApkReader<S> {
+    /// Parse the header and file table from any seekable source, such as a `Cursor` over an
+    /// in-memory buffer or a stream into another archive. The archive starts at the current
+    /// position of `owner`, so an APK embedded in a larger file can be read by seeking to it
+    /// first.
+    ///
+    /// Every entry in the file table is checked against the length of the stream, so that a
+    /// truncated or hostile APK is reported as a [`ReaderError`] instead of causing a large
+    /// allocation.
+    pub fn new(mut owner: S) -> Result<Self, Box<dyn Error>> {
+        let base = owner.stream_position()?;
+        let stream_len = owner.seek(SeekFrom::End(0))?.saturating_sub(base);
+        owner.seek(SeekFrom::Start(base))?;
+        let header = Header::read_from(&mut owner)?;
+        if !header.check_magic() {
+            return Err(Box::new(ReaderError::BadMagic));
+        }
+        if header.field6 != APK_VERSION {
+            return Err(Box::new(ReaderError::UnsupportedVersion(header.field6)));
+        }
+        // file table must fit inside the stream before it's allocated
+        let table_end = Header::SIZE as u64 + header.count as u64 * FileHeader::SIZE as u64;
+        if table_end > stream_len {
+            return Err(Box::new(ReaderError::FileTableOutOfBounds { count: header.count }));
+        }
+        let files = (0..header.count).map(|_| FileHeader::read_from(&mut owner))
+            .collect::<Result<Vec<_>, _>>()?;
+        // each entry must sit between the end of the file table and the end of the stream, and
+        // must not share any bytes with another entry
+        for f in &files {
+            let end = f.offset as u64 + f.file_size as u64;
+            if (f.offset as u64) < table_end || end > stream_len
+                || (f.file_size as usize) < DataHeader::SIZE {
+                return Err(Box::new(ReaderError::EntryOutOfBounds { entry: f.name_lossy().into_owned() }));
+            }
+        }
+        let mut by_offset: Vec<&FileHeader> = files.iter().collect();
+        by_offset.sort_by_key(|f| f.offset);
+        for pair in by_offset.windows(2) {
+            if pair[0].offset as u64 + pair[0].file_size as u64 > pair[1].offset as u64 {
+                return Err(Box::new(ReaderError::EntryOverlap {
+                    entry: pair[1].name_lossy().into_owned(),
+                    other: pair[0].name_lossy().into_owned()
+                }));
+            }
+        }
+        let mut reader = Self { owner, base, header, files, lookup: HashMap::new(), case_insensitive: false };
+        reader.build_lookup();
+        Ok(reader)
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn file_header(&self, index: usize) -> Option<&FileHeader> {
+        self.files.get(index)
+    }
+
+    /// Read an entry's data header, block table and compressed data without decompressing it.
+    pub fn get_raw_entry(&mut self, name: &str) -> Result<RawEntry, Box<dyn Error>> {
+        match self.index_of(name) {
+            Some(i) => self.get_raw_entry_by_index(i),
+            None => Err(Box::new(ReaderError::FileNotFound(name.to_string())))
+        }
+    }
+
+    pub fn get_raw_entry_by_index(&mut self, index: usize) -> Result<RawEntry, Box<dyn Error>> {
+        let f = self.files.get(index).ok_or(ReaderError::IndexOutOfBounds(index))?;
+        let data_header = Self::read_data_header(&mut self.owner, self.base, f)?;
+        let blocks = Self::read_blocks(&mut self.owner, self.base, f, &data_header)?;
+        self.owner.seek(SeekFrom::Start(self.base + f.offset as u64))?;
+        let mut data = vec![0; f.file_size as usize];
+        self.owner.read_exact(&mut data)?;
+        Ok(RawEntry { data_header, blocks, data })
+    }
+
+    // only allocates when names need to be lowercased
+    fn lookup_key<'n>(&self, name: &'n [u8]) -> Cow<'n, [u8]> {
+        match self.case_insensitive {
+            true => Cow::Owned(name.to_ascii_lowercase()),
+            false => Cow::Borrowed(name)
+        }
+    }
+
+    fn build_lookup(&mut self) {
+        let mut lookup = HashMap::with_capacity(self.files.len());
+        for (i, f) in self.files.iter().enumerate() {
+            // if a name appears twice, the first entry wins
+            lookup.entry(self.lookup_key(f.name_bytes()).into()).or_insert(i);
+        }
+        self.lookup = lookup;
+    }
+
+    /// Match names regardless of ASCII case, the same way the game does when it's running on
+    /// Windows.
+    pub fn set_case_insensitive(&mut self, value: bool) {
+        if self.case_insensitive != value {
+            self.case_insensitive = value;
+            self.build_lookup();
+        }
+    }
+
+    pub fn is_case_insensitive(&self) -> bool {
+        self.case_insensitive
+    }
+
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        self.lookup.get(self.lookup_key(name.as_bytes()).as_ref()).copied()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.index_of(name).is_some()
+    }
+
+    pub(crate) fn read_data_header(owner: &mut S, base: u64, f: &FileHeader) -> Result<DataHeader, Box<dyn Error>> {
+        owner.seek(SeekFrom::Start(base + f.offset as u64))?;
+        let mut data_header = [0u8; DataHeader::SIZE];
+        owner.read_exact(&mut data_header)?;
+        if data_header[..4] != APK_DATA_MAGIC.to_le_bytes() {
+            return Err(Box::new(ReaderError::BadDataMagic { entry: f.name_lossy().into_owned() }));
+        }
+        let data_header = DataHeader::from_le_bytes(&data_header)
+            .map_err(|value| ReaderError::UnknownCompressionType { entry: f.name_lossy().into_owned(), value })?;
+        // compressed stream must fit inside the space the file table reserved for this entry
+        if (data_header.header_size as usize) < DataHeader::SIZE
+            || data_header.header_size as u64 + data_header.compressed as u64 > f.file_size as u64 {
+            return Err(Box::new(ReaderError::BadDataHeader { entry: f.name_lossy().into_owned() }));
+        }
+        Ok(data_header)
+    }
+
+    /// Read the block table that follows the data header, checking that every block fits inside
+    /// the space the file table reserved for this entry, that blocks are stored in order without
+    /// overlapping, and that no block claims to decompress to more than its codec could produce
+    /// from it.
+    pub(crate) fn read_blocks(owner: &mut S, base: u64, f: &FileHeader, data_header: &DataHeader)
+        -> Result<Vec<DataBlock>, Box<dyn Error>> {
+        let count = data_header.block_count();
+        let bad_header = || Box::new(ReaderError::BadDataHeader { entry: f.name_lossy().into_owned() });
+        let header_area = DataHeader::header_area_size(count);
+        let blocks = match count {
+            1 => vec![data_header.first_block()],
+            _ => {
+                let limit = data_header.get_size_limit() as u64;
+                let decompressed = data_header.decompressed as u64;
+                if header_area > f.file_size as usize || limit == 0
+                    || decompressed <= (count as u64 - 1) * limit || decompressed > count as u64 * limit {
+                    return Err(bad_header());
+                }
+                owner.seek(SeekFrom::Start(base + f.offset as u64 + 0x20))?;
+                let mut table = vec![0; count * DataBlock::SIZE];
+                owner.read_exact(&mut table)?;
+                data_header.blocks_from_le_bytes(&table)
+            }
+        };
+        // each block has to start after the end of the one before it
+        let mut next = header_area as u64;
+        for (i, block) in blocks.iter().enumerate() {
+            let end = block.offset as u64 + block.compressed as u64;
+            let max_output = data_header.compress_type.max_decompressed_size(block.compressed as usize);
+            if (block.offset as u64) < next || end > f.file_size as u64
+                || data_header.block_range(i).len() as u64 > max_output {
+                return Err(bad_header());
+            }
+            next = end;
+        }
+        Ok(blocks)
+    }
+
+    fn entry_info(owner: &mut S, base: u64, index: usize, f: &FileHeader) -> Result<EntryInfo, Box<dyn Error>> {
+        let data_header = Self::read_data_header(owner, base, f)?;
+        let blocks = Self::read_blocks(owner, base, f, &data_header)?;
+        Self::info_from(index, f, &data_header, &blocks)
+    }
+
+    fn info_from(index: usize, f: &FileHeader, data_header: &DataHeader, blocks: &[DataBlock])
+        -> Result<EntryInfo, Box<dyn Error>> {
+        let name = Self::checked_name(index, f)?.to_string();
+        let compressed_size = blocks.iter().try_fold(0u32, |total, b| total.checked_add(b.compressed))
+            .ok_or_else(|| ReaderError::BadDataHeader { entry: name.clone() })?;
+        Ok(EntryInfo {
+            name,
+            index,
+            offset: f.offset,
+            stored_size: f.file_size,
+            compressed_size,
+            decompressed_size: data_header.decompressed,
+            compression: data_header.compress_type,
+            num_blocks: data_header.get_num_blocks()
+        })
+    }
+
+    /// Iterate over the metadata of each entry in archive order. Data headers are read as the
+    /// iterator advances, and nothing is decompressed.
+    pub fn entries(&mut self) -> Entries<'_, S> {
+        Entries { reader: self, index: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Decompress the entry described by `f`, for an archive that starts at the beginning of
+    /// `owner`.
     pub fn get_file_inner(owner: &mut S, f: &FileHeader) -> Result<Vec<u8>, Box<dyn Error>> {
+        Self::get_file_at(owner, 0, f)
+    }
+
+    fn get_file_at(owner: &mut S, base: u64, f: &FileHeader) -> Result<Vec<u8>, Box<dyn Error>> {
         // get data header
-        owner.seek(SeekFrom::Start(f.offset as u64))?;
-        let mut data_header: MaybeUninit<DataHeader> = MaybeUninit::uninit();
-        owner.read_exact(unsafe { &mut *(data_header.as_mut_ptr() as *mut [u8; size_of::<DataHeader>()]) })?;
-        let data_header = unsafe { data_header.assume_init() };
-        // read compressed stream
-        let mut compressed = Vec::with_capacity(data_header.compressed as usize);
-        unsafe { compressed.set_len(data_header.compressed as usize) };
-        owner.read_exact(&mut compressed)?;
-        // decompress using specified compression algorithm
-        let mut out = Vec::with_capacity(data_header.decompressed as usize);
-        unsafe {
-            out.set_len(data_header.decompressed as usize);
-            decompress_raw(&data_header, compressed.as_slice(), out.as_mut_slice())?;
+        let data_header = Self::read_data_header(owner, base, f)?;
+        let blocks = Self::read_blocks(owner, base, f, &data_header)?;
+        Self::decompress_entry(owner, base, f, &data_header, &blocks)
+    }
+
+    // parses the data header once for both the metadata and decompressing
+    fn read_file_with_info(owner: &mut S, base: u64, index: usize, f: &FileHeader)
+        -> Result<(EntryInfo, Vec<u8>), Box<dyn Error>> {
+        let data_header = Self::read_data_header(owner, base, f)?;
+        let blocks = Self::read_blocks(owner, base, f, &data_header)?;
+        let info = Self::info_from(index, f, &data_header, &blocks)?;
+        let data = Self::decompress_entry(owner, base, f, &data_header, &blocks)?;
+        Ok((info, data))
+    }
+
+    /// Read and decompress each block of an entry whose data header has already been parsed.
+    fn decompress_entry(owner: &mut S, base: u64, f: &FileHeader, data_header: &DataHeader, blocks: &[DataBlock])
+        -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut out = vec![];
+        let mut compressed = vec![];
+        for (i, block) in blocks.iter().enumerate() {
+            // read compressed stream
+            owner.seek(SeekFrom::Start(base + f.offset as u64 + block.offset as u64))?;
+            compressed.resize(block.compressed as usize, 0);
+            owner.read_exact(&mut compressed)?;
+            // decompress using specified compression algorithm
+            decompress_block_to(data_header.compress_type, &compressed, data_header.block_range(i).len(), &mut out)?;
         }
         Ok(out)
     }
 
+    fn open_inner<'b>(owner: &'b mut S, base: u64, f: &FileHeader) -> Result<ExactStream<EntryStream<'b, S>>, Box<dyn Error>> {
+        let data_header = Self::read_data_header(owner, base, f)?;
+        let blocks = Self::read_blocks(owner, base, f, &data_header)?;
+        let size = data_header.decompressed as u64;
+        let stream = match (data_header.compress_type, blocks.len()) {
+            (CompressionType::ZLib, 1) | (CompressionType::ZStandard, 1) => {
+                owner.seek(SeekFrom::Start(base + f.offset as u64 + blocks[0].offset as u64))?;
+                let compressed = owner.take(blocks[0].compressed as u64);
+                match data_header.compress_type {
+                    CompressionType::ZLib => EntryStream::ZLib(flate2::read::ZlibDecoder::new(compressed)),
+                    _ => EntryStream::ZStandard(zstd::stream::read::Decoder::new(compressed)?)
+                }
+            },
+            _ => EntryStream::Blocks(BlockStream {
+                owner, offset: base + f.offset as u64, data_header, blocks,
+                next: 0, compressed: vec![], current: Cursor::new(vec![])
+            })
+        };
+        Ok(ExactStream { inner: stream, expected: size, read: 0 })
+    }
+
+    /// Open a reader that decompresses the named entry incrementally, instead of loading the
+    /// whole entry into memory. Reads fail with [`std::io::ErrorKind::UnexpectedEof`] if the
+    /// entry decompresses to less than its data header says.
+    pub fn open_entry(&mut self, name: &str) -> Result<impl Read + '_, Box<dyn Error>> {
+        let i = self.index_of(name).ok_or_else(|| ReaderError::FileNotFound(name.to_string()))?;
+        Self::open_inner(&mut self.owner, self.base, &self.files[i])
+    }
+
+    /// Decompress the named entry into `writer`, returning the number of bytes written.
+    pub fn extract_to<W: Write + ?Sized>(&mut self, name: &str, writer: &mut W) -> Result<u64, Box<dyn Error>> {
+        let i = self.index_of(name).ok_or_else(|| ReaderError::FileNotFound(name.to_string()))?;
+        self.extract_to_by_index(i, writer)
+    }
+
+    /// Decompress the entry at `index` into `writer`. Unlike [`ApkReader::extract_to`], this
+    /// reaches every entry of an archive that has more than one entry with the same name.
+    pub fn extract_to_by_index<W: Write + ?Sized>(&mut self, index: usize, writer: &mut W) -> Result<u64, Box<dyn Error>> {
+        let f = self.files.get(index).ok_or(ReaderError::IndexOutOfBounds(index))?;
+        let mut stream = Self::open_inner(&mut self.owner, self.base, f)?;
+        match std::io::copy(&mut stream, writer) {
+            Ok(written) => Ok(written),
+            // pass decoding errors on as they are rather than wrapped in an io::Error
+            Err(e) if e.get_ref().is_some_and(|e| e.is::<ReaderError>()) => Err(e.into_inner().unwrap()),
+            Err(e) => Err(Box::new(e))
+        }
+    }
+
     pub fn get_file(&mut self, name: &str) -> Result<Vec<u8>, Box<dyn Error>> {
-        for f in &self.files {
-            if f.get_filename() == name {
-                return Self::get_file_inner(&mut self.owner, f);
-            }
+        match self.index_of(name) {
+            Some(i) => Self::get_file_at(&mut self.owner, self.base, &self.files[i]),
+            None => Err(Box::new(ReaderError::FileNotFound(name.to_string())))
         }
-        Err(Box::new(ReaderError::FileNotFound(name.to_string())))
     }
 
-    pub fn get_all_files(&mut self) -> Result<HashMap<&str, Vec<u8>>, Box<dyn Error>> {
-        let mut files = HashMap::new();
-        for f in &self.files {
-            files.insert(f.get_filename(),Self::get_file_inner(&mut self.owner, f)?);
+    pub fn get_file_by_index(&mut self, index: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        let f = self.files.get(index).ok_or(ReaderError::IndexOutOfBounds(index))?;
+        Self::get_file_at(&mut self.owner, self.base, f)
+    }
+
+    /// Read every compressed stream in archive order, then decompress them across all cores.
+    #[cfg(feature = "rayon")]
+    pub fn get_all_files_parallel(&mut self) -> Result<ExtractedFiles, Box<dyn Error>> {
+        use rayon::prelude::*;
+        let mut compressed = Vec::with_capacity(self.files.len());
+        for (i, f) in self.files.iter().enumerate() {
+            let name = Self::checked_name(i, f)?.to_string();
+            let data_header = Self::read_data_header(&mut self.owner, self.base, f)?;
+            let blocks = Self::read_blocks(&mut self.owner, self.base, f, &data_header)?.iter().map(|block| {
+                self.owner.seek(SeekFrom::Start(self.base + f.offset as u64 + block.offset as u64))?;
+                let mut data = vec![0; block.compressed as usize];
+                self.owner.read_exact(&mut data)?;
+                Ok(data)
+            }).collect::<Result<Vec<_>, std::io::Error>>()?;
+            compressed.push((name, data_header, blocks));
+        }
+        let files = compressed.into_par_iter().map(|(name, data_header, blocks)| {
+            let mut out = vec![];
+            for (i, data) in blocks.iter().enumerate() {
+                decompress_block_to(data_header.compress_type, data, data_header.block_range(i).len(), &mut out)?;
+            }
+            Ok((name, out))
+        }).collect::<Result<Vec<_>, ReaderError>>()?;
+        Ok(files)
+    }
+
+    pub fn get_all_files(&mut self) -> Result<ExtractedFiles, Box<dyn Error>> {
+        let mut files = Vec::with_capacity(self.files.len());
+        for (i, f) in self.files.iter().enumerate() {
+            files.push((Self::checked_name(i, f)?.to_string(), Self::get_file_at(&mut self.owner, self.base, f)?));
         }
         Ok(files)
     }
 
-    pub fn create_file_list(&self) -> String {
+    /// Decompress each entry in archive order, one at a time as the iterator advances.
+    pub fn files(&mut self) -> Files<'_, S> {
+        Files { reader: self, index: 0 }
+    }
+
+    pub fn create_file_list(&self) -> Result<String, ReaderError> {
         let mut file_list = String::new();
-        for f in &self.files {
-            file_list.push_str(f.get_filename());
+        for (i, f) in self.files.iter().enumerate() {
+            file_list.push_str(Self::checked_name(i, f)?);
             file_list.push('\n');
         }
-        file_list
+        Ok(file_list)
+    }
+
+    fn checked_name(index: usize, f: &FileHeader) -> Result<&str, ReaderError> {
+        f.name().map_err(|_| ReaderError::InvalidName { index, name: f.name_lossy().into_owned() })
     }
 }
 
+/// # Safety
+///
+/// `header` must describe `compressed`, and `decompressed` must be large enough to hold the
+/// decompressed output. Entries with more than one block should be decompressed through
+/// [`DataHeader::decompress_from_raw_parts`] instead.
 pub unsafe fn decompress_raw(header: &DataHeader, compressed: &[u8], decompressed: &mut [u8])
     -> Result<(), Box<dyn Error>> {
-    Ok(match header.compress_type {
+    Ok(decompress_block(header.compress_type, compressed, decompressed)?)
+}
+
+/// Decompress a single compressed stream, failing unless it fills `decompressed` exactly. Errors
+/// are `Send` so that this can be called from worker threads.
+pub(crate) fn decompress_block(cmp_type: CompressionType, compressed: &[u8], decompressed: &mut [u8])
+    -> Result<(), ReaderError> {
+    let written = match cmp_type {
         CompressionType::ZLib => {
             let mut decoder = flate2::read::ZlibDecoder::new(compressed);
-            decoder.read_exact(decompressed)?;
+            decoder.read_exact(decompressed).map_err(ReaderError::ZLibError)?;
+            decompressed.len()
         },
         CompressionType::LZ4 => {
             #[cfg(feature = "use-lz4-flex")]
             {
-                lz4_flex::block::decompress_into(compressed, decompressed)?;
+                lz4_flex::block::decompress_into(compressed, decompressed)
+                    .map_err(|e| ReaderError::LZ4Error(e.to_string()))?
             }
             #[cfg(feature = "use-lz4")]
             {
-                lz4::block::decompress_to_buffer(compressed, Some(decompressed.len() as i32), decompressed)?;
+                lz4::block::decompress_to_buffer(compressed, Some(decompressed.len() as i32), decompressed)
+                    .map_err(|e| ReaderError::LZ4Error(e.to_string()))?
             }
         },
         CompressionType::ZStandard => {
-            zstd::zstd_safe::decompress(decompressed, &compressed)
-                .map_err(|e| ReaderError::ZStdError(e))?;
+            zstd::zstd_safe::decompress(decompressed, compressed)
+                .map_err(ReaderError::ZStdError)?
         },
-    })
+    };
+    if written != decompressed.len() {
+        return Err(ReaderError::DecompressedSizeMismatch { expected: decompressed.len(), actual: written });
+    }
+    Ok(())
+}
+
+/// Decompress a single compressed stream onto the end of `out`, failing unless it produces exactly
+/// `size` bytes. zlib and zstd output grows as it's decoded, so a data header that overstates the
+/// decompressed size can't allocate more than the stream really holds. LZ4 blocks can only be
+/// decoded into a buffer of the final size, so that size is checked against the most the block
+/// could expand to first.
+pub(crate) fn decompress_block_to(cmp_type: CompressionType, compressed: &[u8], size: usize, out: &mut Vec<u8>)
+    -> Result<(), ReaderError> {
+    let start = out.len();
+    match cmp_type {
+        CompressionType::ZLib => {
+            let decoder = flate2::read::ZlibDecoder::new(compressed);
+            decoder.take(size as u64).read_to_end(out).map_err(ReaderError::ZLibError)?;
+        },
+        CompressionType::LZ4 => {
+            if size as u64 > cmp_type.max_decompressed_size(compressed.len()) {
+                return Err(ReaderError::LZ4Error(format!("{} byte block can't decompress to {} bytes",
+                    compressed.len(), size)));
+            }
+            out.resize(start + size, 0);
+            return decompress_block(cmp_type, compressed, &mut out[start..]);
+        },
+        CompressionType::ZStandard => {
+            let mut dctx = zstd::zstd_safe::DCtx::create();
+            let mut input = zstd::zstd_safe::InBuffer::around(compressed);
+            loop {
+                // stop as soon as the stream overruns, then report the mismatch below
+                if out.len() - start > size {
+                    break;
+                }
+                out.reserve((start + size - out.len()).clamp(1, 0x20000));
+                let before = (input.pos(), out.len());
+                let remaining = {
+                    let mut output = zstd::zstd_safe::OutBuffer::around_pos(out, out.len());
+                    dctx.decompress_stream(&mut output, &mut input).map_err(ReaderError::ZStdError)?
+                };
+                let finished = remaining == 0 && input.pos() == compressed.len();
+                let stalled = before == (input.pos(), out.len()) && out.len() < out.capacity();
+                if finished || stalled {
+                    break;
+                }
+            }
+        }
+    }
+    if out.len() - start != size {
+        return Err(ReaderError::DecompressedSizeMismatch { expected: size, actual: out.len() - start });
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 pub mod tests {
     use std::error::Error;
-    use crate::read::ApkReader;
+    use std::io::{Cursor, Read, Seek, SeekFrom};
+    use crate::read::{ApkReader, RawEntry, ReaderError};
+    use crate::write::{compress, ApkWriter, CompressionOptions};
+    use crate::serial::{CompressionType, DataHeader, FileHeader, Header};
+
+    /// Lay out an APK the same way as ApkWriter, using zstd for every entry
+    pub(crate) fn build_apk(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut table = Header::new(entries.len()).to_le_bytes().to_vec();
+        let mut data = vec![];
+        let data_start = Header::SIZE + entries.len() * FileHeader::SIZE;
+        for (name, file) in entries {
+            let compressed = zstd::encode_all(*file, zstd::DEFAULT_COMPRESSION_LEVEL).unwrap();
+            let cmp_pad_size = (compressed.len() + 0xf) & !0xf;
+            table.extend_from_slice(&FileHeader::new(name, cmp_pad_size, data_start + data.len()).unwrap().to_le_bytes());
+            data.extend_from_slice(&DataHeader::new(compressed.len(), CompressionType::ZStandard, file.len()).to_le_bytes());
+            data.extend_from_slice(&compressed);
+            data.resize(data.len() + cmp_pad_size - compressed.len(), 0);
+        }
+        table.extend_from_slice(&data);
+        table
+    }
+
+    /// APK with a single entry split into blocks of `size_limit` bytes, laid out as described on
+    /// [`crate::serial::DataBlock`]. The writer only stores single blocks, so these are put together by hand.
+    pub(crate) fn build_blocks_apk(name: &str, cmp_type: CompressionType, file: &[u8], size_limit: usize)
+        -> Result<Vec<u8>, Box<dyn Error>> {
+        let compressed = file.chunks(size_limit).map(|c| compress(CompressionOptions::new(cmp_type), c))
+            .collect::<Result<Vec<_>, _>>()?;
+        let sizes: Vec<usize> = compressed.iter().map(|c| c.len()).collect();
+        let (header, _) = DataHeader::new_blocks(cmp_type, file.len(), size_limit, &sizes)?;
+        let compressed: Vec<&[u8]> = compressed.iter().map(|c| c.as_slice()).collect();
+        let mut writer = ApkWriter::new(Cursor::new(vec![]));
+        writer.add_raw_entry(name, RawEntry::with_blocks(&header, &compressed)?)?;
+        writer.save()?;
+        Ok(writer.into_inner().into_inner())
+    }
 
     #[test]
     fn test_read() -> Result<(), Box<dyn Error>> {
@@ -128,4 +777,287 @@ pub mod tests {
         std::fs::write("E:/Metaphor/base_cpk/COMMON/ui/ss/01_grandtrad.dds", &file)?;
         Ok(())
     }
+
+    #[test]
+    fn test_read_from_memory() -> Result<(), Box<dyn Error>> {
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&Header::new(0).to_le_bytes());
+        let apk = ApkReader::new(Cursor::new(bytes))?;
+        assert_eq!(apk.create_file_list()?, "");
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_embedded() -> Result<(), Box<dyn Error>> {
+        let apk = build_apk(&[("a.dds", b"first"), ("b.dds", b"second")]);
+        let mut bytes = vec![0xcc; 0x10];
+        bytes.extend_from_slice(&apk);
+        bytes.extend_from_slice(&[0xcc; 0x20]);
+        let mut stream = Cursor::new(bytes);
+        stream.seek(SeekFrom::Start(0x10))?;
+        let mut reader = ApkReader::new(stream)?;
+        assert_eq!(reader.get_file("b.dds")?, b"second");
+        let mut out = vec![];
+        reader.extract_to("a.dds", &mut out)?;
+        assert_eq!(out, b"first");
+        assert_eq!(ApkReader::from_bytes(&apk)?.get_all_files()?.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_bad_magic() {
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&Header::new(0).to_le_bytes());
+        bytes[0] = b'X';
+        let err = ApkReader::new(Cursor::new(bytes)).err().unwrap();
+        assert!(matches!(err.downcast_ref::<ReaderError>(), Some(ReaderError::BadMagic)));
+    }
+
+    #[test]
+    fn test_read_unsupported_version() {
+        let mut header = Header::new(0);
+        header.set_field6(2);
+        let err = ApkReader::new(Cursor::new(header.to_le_bytes().to_vec())).err().unwrap();
+        assert!(matches!(err.downcast_ref::<ReaderError>(), Some(ReaderError::UnsupportedVersion(2))));
+    }
+
+    #[test]
+    fn test_read_bad_data_magic() -> Result<(), Box<dyn Error>> {
+        let mut bytes = build_apk(&[("a.dds", b"first")]);
+        bytes[Header::SIZE + FileHeader::SIZE] ^= 0xff;
+        let mut apk = ApkReader::new(Cursor::new(bytes))?;
+        let err = apk.get_file("a.dds").err().unwrap();
+        assert!(matches!(err.downcast_ref::<ReaderError>(), Some(ReaderError::BadDataMagic { entry }) if entry == "a.dds"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_truncated() {
+        // file table claims more entries than the stream can hold
+        let bytes = Header::new(1000).to_le_bytes().to_vec();
+        let err = ApkReader::new(Cursor::new(bytes)).err().unwrap();
+        assert!(matches!(err.downcast_ref::<ReaderError>(), Some(ReaderError::FileTableOutOfBounds { count: 1000 })));
+        // entry points past the end of the stream
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&Header::new(1).to_le_bytes());
+        bytes.extend_from_slice(&FileHeader::new("a.dds", 0x1000, 0x130).unwrap().to_le_bytes());
+        let err = ApkReader::new(Cursor::new(bytes)).err().unwrap();
+        assert!(matches!(err.downcast_ref::<ReaderError>(), Some(ReaderError::EntryOutOfBounds { .. })));
+    }
+
+    #[test]
+    fn test_read_entry_overlap() {
+        let mut bytes = build_apk(&[("a.dds", b"first"), ("b.dds", b"second")]);
+        // point the second entry at the first one's data
+        let offset = (Header::SIZE + 2 * FileHeader::SIZE) as u32;
+        let field = Header::SIZE + FileHeader::SIZE + 0x118;
+        bytes[field..field + 4].copy_from_slice(&offset.to_le_bytes());
+        let err = ApkReader::new(Cursor::new(bytes)).err().unwrap();
+        assert!(matches!(err.downcast_ref::<ReaderError>(), Some(ReaderError::EntryOverlap { .. })));
+    }
+
+    #[test]
+    fn test_read_bad_data_header() -> Result<(), Box<dyn Error>> {
+        let data = Header::SIZE + FileHeader::SIZE;
+        // (field offset in the data header, patched value)
+        for (field, value) in [(0x24, 0x10u32), (0x20, 0x1000), (0xc, u32::MAX)] {
+            let mut bytes = build_apk(&[("a.dds", b"first")]);
+            bytes[data + field..data + field + 4].copy_from_slice(&value.to_le_bytes());
+            let mut apk = ApkReader::new(Cursor::new(bytes))?;
+            let err = apk.get_file("a.dds").err().unwrap();
+            assert!(matches!(err.downcast_ref::<ReaderError>(), Some(ReaderError::BadDataHeader { .. })));
+            assert!(apk.entries().next().unwrap().is_err());
+            assert!(apk.extract_to("a.dds", &mut vec![]).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_entry_short_stream() -> Result<(), Box<dyn Error>> {
+        let mut writer = ApkWriter::new(Cursor::new(vec![]));
+        writer.add_internal_file_with_compression("a.dds", CompressionType::ZLib, &[5; 100])?;
+        writer.save()?;
+        let mut bytes = writer.into_inner().into_inner();
+        let data = Header::SIZE + FileHeader::SIZE;
+        bytes[data + 0xc..data + 0x10].copy_from_slice(&200u32.to_le_bytes());
+        let mut apk = ApkReader::new(Cursor::new(bytes))?;
+        let mut out = vec![];
+        let err = apk.open_entry("a.dds")?.read_to_end(&mut out).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(out.len(), 100);
+        let err = apk.extract_to("a.dds", &mut vec![]).err().unwrap();
+        assert!(matches!(err.downcast_ref::<ReaderError>(),
+            Some(ReaderError::DecompressedSizeMismatch { expected: 200, actual: 100 })));
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_blocks() -> Result<(), Box<dyn Error>> {
+        let texture: Vec<u8> = (0..0x2800).map(|i| (i % 251) as u8).collect();
+        for cmp_type in [CompressionType::ZLib, CompressionType::LZ4, CompressionType::ZStandard] {
+            let mut apk = ApkReader::new(Cursor::new(build_blocks_apk("a.dds", cmp_type, &texture, 0x1000)?))?;
+            assert_eq!(apk.entries().next().unwrap()?.num_blocks, 3);
+            assert_eq!(apk.get_file("a.dds")?, texture);
+            let mut streamed = vec![];
+            apk.extract_to("a.dds", &mut streamed)?;
+            assert_eq!(streamed, texture);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_overlapping_blocks() -> Result<(), Box<dyn Error>> {
+        let texture: Vec<u8> = (0..0x2800).map(|i| (i % 251) as u8).collect();
+        let original = build_blocks_apk("a.dds", CompressionType::LZ4, &texture, 0x1000)?;
+        // every block in the table after the first is pointed back at the first block
+        let table = Header::SIZE + FileHeader::SIZE + 0x20;
+        let mut bytes = original.clone();
+        let first = bytes[table..table + 8].to_vec();
+        for i in 1..3 {
+            bytes[table + i * 8..table + i * 8 + 8].copy_from_slice(&first);
+        }
+        let mut apk = ApkReader::new(Cursor::new(bytes))?;
+        let err = apk.entries().next().unwrap().err().unwrap();
+        assert!(matches!(err.downcast_ref::<ReaderError>(), Some(ReaderError::BadDataHeader { .. })));
+        assert!(apk.get_file("a.dds").is_err());
+        // blocks swapped around are rejected too
+        let mut bytes = original;
+        let (second, third) = (bytes[table + 8..table + 16].to_vec(), bytes[table + 16..table + 24].to_vec());
+        bytes[table + 8..table + 16].copy_from_slice(&third);
+        bytes[table + 16..table + 24].copy_from_slice(&second);
+        let mut apk = ApkReader::new(Cursor::new(bytes))?;
+        assert!(apk.entries().next().unwrap().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_overstated_size() -> Result<(), Box<dyn Error>> {
+        // a 1 byte LZ4 block claiming to decompress to 4GiB is rejected before anything is allocated
+        let mut writer = ApkWriter::new(Cursor::new(vec![]));
+        writer.add_internal_file_with_compression("a.dds", CompressionType::LZ4, &[])?;
+        writer.save()?;
+        let mut bytes = writer.into_inner().into_inner();
+        let data = Header::SIZE + FileHeader::SIZE;
+        assert_eq!(bytes[data + 0x20..data + 0x24], 1u32.to_le_bytes());
+        bytes[data + 0xc..data + 0x10].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut apk = ApkReader::new(Cursor::new(bytes))?;
+        let err = apk.get_file("a.dds").err().unwrap();
+        assert!(matches!(err.downcast_ref::<ReaderError>(), Some(ReaderError::BadDataHeader { .. })));
+        // a size the codec could reach is only reported once the stream runs out
+        let mut bytes = build_apk(&[("a.dds", b"first")]);
+        bytes[data + 0xc..data + 0x10].copy_from_slice(&0x10000u32.to_le_bytes());
+        let mut apk = ApkReader::new(Cursor::new(bytes))?;
+        let err = apk.get_file("a.dds").err().unwrap();
+        assert!(matches!(err.downcast_ref::<ReaderError>(),
+            Some(ReaderError::DecompressedSizeMismatch { expected: 0x10000, actual: 5 })));
+        Ok(())
+    }
+
+    #[test]
+    fn test_entries() -> Result<(), Box<dyn Error>> {
+        let bytes = build_apk(&[("a.dds", &[1; 0x100]), ("b.dds", b"texture")]);
+        let mut apk = ApkReader::new(Cursor::new(bytes))?;
+        let entries = apk.entries().collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "a.dds");
+        assert_eq!(entries[0].offset as usize, Header::SIZE + 2 * FileHeader::SIZE);
+        assert_eq!(entries[0].decompressed_size, 0x100);
+        assert_eq!(entries[1].name, "b.dds");
+        assert_eq!(entries[1].index, 1);
+        assert_eq!(entries[1].compression, CompressionType::ZStandard);
+        assert_eq!(entries[1].num_blocks, 1);
+        assert_eq!(entries[1].stored_size, entries[1].compressed_size.next_multiple_of(0x10) + 0x30);
+        assert_eq!(apk.get_file("b.dds")?, b"texture");
+        Ok(())
+    }
+
+    #[test]
+    fn test_lookup() -> Result<(), Box<dyn Error>> {
+        let bytes = build_apk(&[("a.dds", b"first"), ("B.dds", b"second")]);
+        let mut apk = ApkReader::new(Cursor::new(bytes))?;
+        assert_eq!(apk.index_of("B.dds"), Some(1));
+        assert!(!apk.contains("b.dds"));
+        assert_eq!(apk.get_file_by_index(0)?, b"first");
+        let err = apk.get_file_by_index(2).err().unwrap();
+        assert!(matches!(err.downcast_ref::<ReaderError>(), Some(ReaderError::IndexOutOfBounds(2))));
+        apk.set_case_insensitive(true);
+        assert!(apk.contains("A.DDS"));
+        assert_eq!(apk.get_file("b.DDS")?, b"second");
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_to() -> Result<(), Box<dyn Error>> {
+        let texture: Vec<u8> = (0..0x10000).map(|i| (i % 251) as u8).collect();
+        let bytes = build_apk(&[("a.dds", &texture), ("b.dds", b"second")]);
+        let mut apk = ApkReader::new(Cursor::new(bytes))?;
+        let mut out = vec![];
+        assert_eq!(apk.extract_to("a.dds", &mut out)?, texture.len() as u64);
+        assert_eq!(out, texture);
+        let mut out = vec![];
+        apk.open_entry("b.dds")?.read_to_end(&mut out)?;
+        assert_eq!(out, b"second");
+        // duplicate names can only be told apart by index
+        let bytes = build_apk(&[("a.dds", b"first"), ("a.dds", b"second")]);
+        let mut apk = ApkReader::new(Cursor::new(bytes))?;
+        let mut out = vec![];
+        apk.extract_to_by_index(1, &mut out)?;
+        assert_eq!(out, b"second");
+        let mut out = vec![];
+        apk.extract_to("a.dds", &mut out)?;
+        assert_eq!(out, b"first");
+        assert!(apk.extract_to_by_index(2, &mut out).is_err());
+        Ok(())
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_get_all_files_parallel() -> Result<(), Box<dyn Error>> {
+        let bytes = build_apk(&[("a.dds", &[1; 0x1000]), ("b.dds", b"second"), ("c.dds", &[3; 0x20])]);
+        let mut apk = ApkReader::new(Cursor::new(bytes))?;
+        let files = apk.get_all_files_parallel()?;
+        let names: Vec<_> = files.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["a.dds", "b.dds", "c.dds"]);
+        assert_eq!(files[1].1, b"second");
+        assert_eq!(files[2].1, [3; 0x20]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_files_in_order() -> Result<(), Box<dyn Error>> {
+        let names = ["d.dds", "b.dds", "a.dds", "c.dds", "e.dds"];
+        let entries: Vec<_> = names.iter().map(|n| (*n, n.as_bytes())).collect();
+        let mut apk = ApkReader::new(Cursor::new(build_apk(&entries)))?;
+        let files = apk.get_all_files()?;
+        assert!(files.iter().map(|(n, _)| n.as_str()).eq(names));
+        for (i, file) in apk.files().enumerate() {
+            let (info, data) = file?;
+            assert_eq!(info.index, i);
+            assert_eq!(data, names[i].as_bytes());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_files_reads_data_header_once() -> Result<(), Box<dyn Error>> {
+        struct CountSeeks(Cursor<Vec<u8>>, usize);
+        impl Read for CountSeeks {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                self.0.read(buf)
+            }
+        }
+        impl Seek for CountSeeks {
+            fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+                self.1 += 1;
+                self.0.seek(pos)
+            }
+        }
+        let bytes = build_apk(&[("a.dds", b"first"), ("b.dds", b"second")]);
+        let mut apk = ApkReader::new(CountSeeks(Cursor::new(bytes), 0))?;
+        let before = apk.owner.1;
+        assert_eq!(apk.files().count(), 2);
+        // one seek to the data header and one to the compressed block for each entry
+        assert_eq!(apk.owner.1 - before, 4);
+        Ok(())
+    }
 }
\ No newline at end of file
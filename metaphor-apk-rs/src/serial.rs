@@ -1,88 +1,188 @@
-use std::ffi::CStr;
+use std::borrow::Cow;
 use std::fmt::{Debug, Formatter};
+use std::str::Utf8Error;
+use std::io::{Read, Write};
+use std::ops::Range;
+use crate::write::{validate_name, WriterError};
+
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
 
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Header {
     magic: [u8; 6],
-    field6: u16,
+    pub(crate) field6: u16,
     pub(crate) count: u32,
     reserve: u32
 }
 
 pub(crate) static APK_MAGIC: [u8; 6] = [0x50, 0x41, 0x43, 0x4b, 0, 0];
+pub(crate) static APK_VERSION: u16 = 1;
 
 impl Header {
+    pub const SIZE: usize = 0x10;
+
     pub fn check_magic(&self) -> bool {
         self.magic == APK_MAGIC
     }
 
+    pub fn get_field6(&self) -> u16 {
+        self.field6
+    }
+    pub fn set_field6(&mut self, value: u16) {
+        self.field6 = value;
+    }
+    pub fn get_reserve(&self) -> u32 {
+        self.reserve
+    }
+    pub fn set_reserve(&mut self, value: u32) {
+        self.reserve = value;
+    }
+
     pub fn new(count: usize) -> Self {
         Self {
             magic: APK_MAGIC,
-            field6: 1,
+            field6: APK_VERSION,
             count: count as u32,
             reserve: 0
         }
     }
 
-    pub fn to_bytes(&self) -> &[u8] {
-        unsafe { std::slice::from_raw_parts(&raw const *self as _, size_of::<Self>()) }
+    pub fn from_le_bytes(bytes: &[u8; Self::SIZE]) -> Self {
+        Self {
+            magic: bytes[..6].try_into().unwrap(),
+            field6: u16_at(bytes, 0x6),
+            count: u32_at(bytes, 0x8),
+            reserve: u32_at(bytes, 0xc)
+        }
     }
-}
 
-const _: () = {
-    ["Size of APK Header"][size_of::<Header>() - 0x10];
-};
+    pub fn to_le_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[..0x6].copy_from_slice(&self.magic);
+        bytes[0x6..0x8].copy_from_slice(&self.field6.to_le_bytes());
+        bytes[0x8..0xc].copy_from_slice(&self.count.to_le_bytes());
+        bytes[0xc..0x10].copy_from_slice(&self.reserve.to_le_bytes());
+        bytes
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        let mut bytes = [0; Self::SIZE];
+        reader.read_exact(&mut bytes)?;
+        Ok(Self::from_le_bytes(&bytes))
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(&self.to_le_bytes())
+    }
+}
 
 #[repr(C)]
+#[derive(Clone)]
 pub struct FileHeader {
-    filename: [i8; 0x100],
+    filename: [u8; 0x100],
     pub(crate) file_size: u32,
     unk: [u32; 5],
     pub(crate) offset: u32,
     unk2: u32
 }
 
-const _: () = {
-    ["Size of File Header"][size_of::<FileHeader>() - 0x120];
-};
-
 impl FileHeader {
-    pub fn get_filename(&self) -> &str {
-        unsafe { CStr::from_ptr(self.filename.as_ptr()).to_str().unwrap() }
+    pub const SIZE: usize = 0x120;
+    /// Longest name that fits in the filename field with a NUL terminator.
+    pub const MAX_NAME_LEN: usize = 0xff;
+
+    /// Bytes of the filename up to the first NUL, or the whole field if it isn't terminated.
+    pub fn name_bytes(&self) -> &[u8] {
+        let len = self.filename.iter().position(|c| *c == 0).unwrap_or(self.filename.len());
+        &self.filename[..len]
+    }
+
+    pub fn name(&self) -> Result<&str, Utf8Error> {
+        std::str::from_utf8(self.name_bytes())
+    }
+
+    /// Filename with any invalid UTF-8 replaced, for use in messages.
+    pub fn name_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.name_bytes())
     }
 
-    pub fn new(name: &str, file_size: usize, offset: usize) -> Self {
+    pub fn get_unk(&self) -> [u32; 5] {
+        self.unk
+    }
+    pub fn set_unk(&mut self, value: [u32; 5]) {
+        self.unk = value;
+    }
+    pub fn get_unk2(&self) -> u32 {
+        self.unk2
+    }
+    pub fn set_unk2(&mut self, value: u32) {
+        self.unk2 = value;
+    }
+
+    /// Fails if the name is empty, contains a NUL or path separator, or doesn't fit in the
+    /// filename field with its NUL terminator.
+    pub fn new(name: &str, file_size: usize, offset: usize) -> Result<Self, WriterError> {
+        validate_name(name)?;
         let mut filename = [0; 0x100];
-        unsafe { std::ptr::copy_nonoverlapping(name.as_ptr() as _, filename.as_mut_ptr(), name.len()) };
-        Self {
+        filename[..name.len()].copy_from_slice(name.as_bytes());
+        Ok(Self {
             filename,
-            file_size: (file_size + size_of::<DataHeader>()) as u32,
+            file_size: (file_size + DataHeader::SIZE) as u32,
             unk: [0; 5],
             offset: offset as u32,
             unk2: 0
+        })
+    }
+
+    pub fn from_le_bytes(bytes: &[u8; Self::SIZE]) -> Self {
+        Self {
+            filename: bytes[..0x100].try_into().unwrap(),
+            file_size: u32_at(bytes, 0x100),
+            unk: std::array::from_fn(|i| u32_at(bytes, 0x104 + i * 4)),
+            offset: u32_at(bytes, 0x118),
+            unk2: u32_at(bytes, 0x11c)
+        }
+    }
+
+    pub fn to_le_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[..0x100].copy_from_slice(&self.filename);
+        bytes[0x100..0x104].copy_from_slice(&self.file_size.to_le_bytes());
+        for (i, unk) in self.unk.iter().enumerate() {
+            bytes[0x104 + i * 4..0x108 + i * 4].copy_from_slice(&unk.to_le_bytes());
         }
+        bytes[0x118..0x11c].copy_from_slice(&self.offset.to_le_bytes());
+        bytes[0x11c..0x120].copy_from_slice(&self.unk2.to_le_bytes());
+        bytes
     }
 
-    pub fn to_bytes(&self) -> &[u8] {
-        unsafe { std::slice::from_raw_parts(&raw const *self as _, size_of::<Self>()) }
+    pub fn read_from<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        let mut bytes = [0; Self::SIZE];
+        reader.read_exact(&mut bytes)?;
+        Ok(Self::from_le_bytes(&bytes))
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(&self.to_le_bytes())
     }
 }
 
 impl Debug for FileHeader {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(f, " FileHeader {{ name: {}, file_size: {}, offset: {} }}",
-            self.get_filename(), self.file_size, self.offset)
+            self.name_lossy(), self.file_size, self.offset)
     }
 }
 
-const _: () = {
-    ["Size of file header"][size_of::<FileHeader>() - 0x120];
-};
-
 #[repr(C, align(16))]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DataHeader {
     magic: u32,
     pub(crate) compress_type: CompressionType,
@@ -105,6 +205,56 @@ impl DataHeader {
     pub fn get_length(&self) -> u32 {
         self.length
     }
+    pub fn get_compressed_size(&self) -> u32 {
+        self.compressed
+    }
+    pub fn get_num_blocks(&self) -> u16 {
+        self.num_blocks
+    }
+    pub fn get_size_limit(&self) -> u32 {
+        self.size_limit
+    }
+    pub fn get_unk(&self) -> [u32; 3] {
+        self.unk
+    }
+    pub fn set_unk(&mut self, value: [u32; 3]) {
+        self.unk = value;
+    }
+    /// Number of compressed blocks. Older archives may store 0 for a single block.
+    pub fn block_count(&self) -> usize {
+        self.num_blocks.max(1) as usize
+    }
+
+    /// Block described by the last two fields of the header.
+    pub fn first_block(&self) -> DataBlock {
+        DataBlock { compressed: self.compressed, offset: self.header_size }
+    }
+
+    /// Range of the decompressed output that a block fills in.
+    pub fn block_range(&self, index: usize) -> Range<usize> {
+        if self.block_count() == 1 {
+            return 0..self.decompressed as usize;
+        }
+        let limit = self.size_limit as usize;
+        let start = (index * limit).min(self.decompressed as usize);
+        start..(start + limit).min(self.decompressed as usize)
+    }
+
+    /// Size of the data header together with its block table.
+    pub fn header_area_size(num_blocks: usize) -> usize {
+        (Self::BLOCK_TABLE + num_blocks.max(1) * DataBlock::SIZE + 0xf) & !0xf
+    }
+
+    /// Parse the block table, given the bytes starting at offset 0x20 of the data header.
+    pub fn blocks_from_le_bytes(&self, table: &[u8]) -> Vec<DataBlock> {
+        table.chunks_exact(DataBlock::SIZE).take(self.block_count())
+            .map(|b| DataBlock { compressed: u32_at(b, 0), offset: u32_at(b, 4) }).collect()
+    }
+
+    /// # Safety
+    ///
+    /// The header must be followed in memory by its compressed data, as it is when the APK is
+    /// loaded by the game. This only returns the first block, see [`DataHeader::get_blocks`].
     pub unsafe fn get_data(&self) -> &[u8] {
         unsafe { std::slice::from_raw_parts(
             (&raw const *self as *const u8).add(self.header_size as usize),
@@ -112,9 +262,28 @@ impl DataHeader {
         ) }
     }
 
+    /// # Safety
+    ///
+    /// See [`DataHeader::get_data`].
+    pub unsafe fn get_blocks(&self) -> Vec<DataBlock> {
+        let table = unsafe { std::slice::from_raw_parts(
+            (&raw const *self as *const u8).add(Self::BLOCK_TABLE),
+            self.block_count() * DataBlock::SIZE
+        ) };
+        self.blocks_from_le_bytes(table)
+    }
+
+    /// # Safety
+    ///
+    /// See [`DataHeader::get_data`].
     pub unsafe fn decompress_from_raw_parts(&self, decompressed: &mut [u8]) {
-        let data = unsafe { self.get_data() };
-        unsafe { crate::read::decompress_raw(self, data, decompressed).unwrap() };
+        for (i, block) in unsafe { self.get_blocks() }.iter().enumerate() {
+            let data = unsafe { std::slice::from_raw_parts(
+                (&raw const *self as *const u8).add(block.offset as usize),
+                block.compressed as usize
+            ) };
+            crate::read::decompress_block(self.compress_type, data, &mut decompressed[self.block_range(i)]).unwrap();
+        }
     }
 }
 
@@ -126,9 +295,58 @@ pub enum CompressionType {
     ZStandard = 2
 }
 
+impl CompressionType {
+    /// Most bytes that `compressed` bytes of this codec can decompress to. An LZ4 block expands
+    /// by at most 255 times, deflate by at most 1032 times, and a zstd RLE block can turn 4 bytes
+    /// into 128KiB.
+    pub fn max_decompressed_size(self, compressed: usize) -> u64 {
+        let ratio = match self {
+            Self::ZLib => 1032,
+            Self::LZ4 => 255,
+            Self::ZStandard => 0x8000
+        };
+        compressed as u64 * ratio
+    }
+}
+
+impl TryFrom<u16> for CompressionType {
+    type Error = u16;
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::ZLib),
+            1 => Ok(Self::LZ4),
+            2 => Ok(Self::ZStandard),
+            v => Err(v)
+        }
+    }
+}
+
 pub(crate) static APK_DATA_MAGIC: u32 = 0x305a5a5a;
 
+/// One independently compressed block of an entry.
+///
+/// The last two fields of [`DataHeader`] describe the first block. Entries split into more than
+/// one block continue that table straight after it, and the table is padded to 0x10 bytes. Every
+/// block decompresses to `size_limit` bytes, except for the last which holds whatever is left.
+///
+/// This layout is inferred from the fields of the data header and hasn't been checked against a
+/// multi-block entry from the game's own archives, so multi-block entries can be read but are
+/// never written. [`crate::write::ApkWriter`] stores every entry as a single block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataBlock {
+    pub compressed: u32,
+    /// Start of the compressed block, relative to the start of the data header
+    pub offset: u32
+}
+
+impl DataBlock {
+    pub const SIZE: usize = 0x8;
+}
+
 impl DataHeader {
+    pub const SIZE: usize = 0x30;
+    const BLOCK_TABLE: usize = 0x20;
+
     pub fn check_magic(&self) -> bool {
         self.magic == APK_DATA_MAGIC
     }
@@ -140,17 +358,170 @@ impl DataHeader {
             num_blocks: 1,
             size_limit: 0,
             decompressed: dcmp_size as u32,
-            length: (cmp_size + size_of::<Self>()) as u32,
+            length: (cmp_size + Self::SIZE) as u32,
             unk: [0; 3],
             compressed: cmp_size as u32,
-            header_size: size_of::<Self>() as u32
+            header_size: Self::SIZE as u32
+        }
+    }
+
+    /// Header for an entry split into blocks of `size_limit` decompressed bytes, with each
+    /// compressed block laid out one after another after the block table. Fails if there are no
+    /// blocks, or more than `num_blocks` can count.
+    pub(crate) fn new_blocks(cmp_type: CompressionType, dcmp_size: usize, size_limit: usize,
+        cmp_sizes: &[usize]) -> Result<(Self, Vec<DataBlock>), WriterError> {
+        if cmp_sizes.is_empty() || cmp_sizes.len() > u16::MAX as usize {
+            return Err(WriterError::InvalidBlockCount(cmp_sizes.len()));
+        }
+        let mut pointer = Self::header_area_size(cmp_sizes.len());
+        let blocks: Vec<DataBlock> = cmp_sizes.iter().map(|size| {
+            let block = DataBlock { compressed: *size as u32, offset: pointer as u32 };
+            pointer += size;
+            block
+        }).collect();
+        let mut header = Self::new(cmp_sizes[0], cmp_type, dcmp_size);
+        if blocks.len() > 1 {
+            header.num_blocks = blocks.len() as u16;
+            header.size_limit = size_limit as u32;
+        }
+        header.length = pointer as u32;
+        header.header_size = blocks[0].offset;
+        Ok((header, blocks))
+    }
+
+    /// Write the header followed by the rest of its block table.
+    pub(crate) fn write_blocks_to<W: Write>(&self, blocks: &[DataBlock], writer: &mut W) -> std::io::Result<()> {
+        let mut bytes = self.to_le_bytes()[..Self::BLOCK_TABLE].to_vec();
+        for block in blocks {
+            bytes.extend_from_slice(&block.compressed.to_le_bytes());
+            bytes.extend_from_slice(&block.offset.to_le_bytes());
+        }
+        bytes.resize(Self::header_area_size(blocks.len()), 0);
+        writer.write_all(&bytes)
+    }
+
+    /// Fails with the raw compression type if it isn't a known [`CompressionType`].
+    pub fn from_le_bytes(bytes: &[u8; Self::SIZE]) -> Result<Self, u16> {
+        Ok(Self {
+            magic: u32_at(bytes, 0x0),
+            compress_type: CompressionType::try_from(u16_at(bytes, 0x4))?,
+            num_blocks: u16_at(bytes, 0x6),
+            size_limit: u32_at(bytes, 0x8),
+            decompressed: u32_at(bytes, 0xc),
+            length: u32_at(bytes, 0x10),
+            unk: std::array::from_fn(|i| u32_at(bytes, 0x14 + i * 4)),
+            compressed: u32_at(bytes, 0x20),
+            header_size: u32_at(bytes, 0x24)
+        })
+    }
+
+    pub fn to_le_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0x0..0x4].copy_from_slice(&self.magic.to_le_bytes());
+        bytes[0x4..0x6].copy_from_slice(&(self.compress_type as u16).to_le_bytes());
+        bytes[0x6..0x8].copy_from_slice(&self.num_blocks.to_le_bytes());
+        bytes[0x8..0xc].copy_from_slice(&self.size_limit.to_le_bytes());
+        bytes[0xc..0x10].copy_from_slice(&self.decompressed.to_le_bytes());
+        bytes[0x10..0x14].copy_from_slice(&self.length.to_le_bytes());
+        for (i, unk) in self.unk.iter().enumerate() {
+            bytes[0x14 + i * 4..0x18 + i * 4].copy_from_slice(&unk.to_le_bytes());
         }
+        bytes[0x20..0x24].copy_from_slice(&self.compressed.to_le_bytes());
+        bytes[0x24..0x28].copy_from_slice(&self.header_size.to_le_bytes());
+        bytes
     }
-    pub fn to_bytes(&self) -> &[u8] {
-        unsafe { std::slice::from_raw_parts(&raw const *self as _, size_of::<Self>()) }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(&self.to_le_bytes())
     }
 }
 
-const _: () = {
-    ["Size of file header"][size_of::<DataHeader>() - 0x30];
-};
\ No newline at end of file
+#[cfg(test)]
+pub mod tests {
+    use crate::serial::{CompressionType, DataHeader, FileHeader, Header};
+    use crate::write::WriterError;
+
+    #[test]
+    fn test_header_round_trip() {
+        assert_eq!(size_of::<Header>(), Header::SIZE);
+        let bytes = Header::new(3).to_le_bytes();
+        assert_eq!(&bytes[..8], &[0x50, 0x41, 0x43, 0x4b, 0, 0, 1, 0]);
+        assert_eq!(&bytes[8..12], &3u32.to_le_bytes());
+        assert_eq!(Header::from_le_bytes(&bytes).to_le_bytes(), bytes);
+    }
+
+    #[test]
+    fn test_file_header_round_trip() {
+        assert_eq!(size_of::<FileHeader>(), FileHeader::SIZE);
+        let mut bytes: [u8; FileHeader::SIZE] = std::array::from_fn(|i| i as u8);
+        bytes[0x10] = 0;
+        let header = FileHeader::from_le_bytes(&bytes);
+        assert_eq!(header.file_size, u32::from_le_bytes([0, 1, 2, 3]));
+        assert_eq!(header.offset, u32::from_le_bytes([0x18, 0x19, 0x1a, 0x1b]));
+        assert_eq!(header.to_le_bytes(), bytes);
+    }
+
+    #[test]
+    fn test_file_header_name() {
+        let mut bytes = [0; FileHeader::SIZE];
+        bytes[..5].copy_from_slice(b"a.dds");
+        assert_eq!(FileHeader::from_le_bytes(&bytes).name(), Ok("a.dds"));
+        // not terminated
+        bytes[..0x100].fill(b'a');
+        assert_eq!(FileHeader::from_le_bytes(&bytes).name_bytes().len(), 0x100);
+        // not UTF-8
+        bytes[0] = 0xff;
+        assert!(FileHeader::from_le_bytes(&bytes).name().is_err());
+    }
+
+    #[test]
+    fn test_file_header_new() {
+        let name = "a".repeat(FileHeader::MAX_NAME_LEN);
+        let header = FileHeader::new(&name, 0x10, 0x130).unwrap();
+        assert_eq!(header.name(), Ok(name.as_str()));
+        assert_eq!(header.to_le_bytes()[0xff], 0);
+        for name in ["a".repeat(0x100), "a".repeat(0x200)] {
+            assert!(matches!(FileHeader::new(&name, 0x10, 0x130), Err(WriterError::NameTooLong { .. })));
+        }
+        assert!(matches!(FileHeader::new("ui/a.dds", 0x10, 0x130), Err(WriterError::InvalidName(_))));
+    }
+
+    #[test]
+    fn test_data_header_round_trip() {
+        assert_eq!(size_of::<DataHeader>(), DataHeader::SIZE);
+        let bytes = DataHeader::new(0x20, CompressionType::ZStandard, 0x80).to_le_bytes();
+        let header = DataHeader::from_le_bytes(&bytes).unwrap();
+        assert!(header.check_magic());
+        assert_eq!(header.get_compress_type(), CompressionType::ZStandard);
+        assert_eq!(header.compressed, 0x20);
+        assert_eq!(header.header_size, 0x30);
+        assert_eq!(header.to_le_bytes(), bytes);
+        let mut bad = bytes;
+        bad[4] = 7;
+        assert_eq!(DataHeader::from_le_bytes(&bad).err(), Some(7));
+    }
+
+    #[test]
+    fn test_data_header_blocks() {
+        let (header, blocks) = DataHeader::new_blocks(CompressionType::LZ4, 0x2800, 0x1000, &[0x10, 0x20, 0x8]).unwrap();
+        assert_eq!(header.block_count(), 3);
+        assert_eq!(header.block_range(1), 0x1000..0x2000);
+        assert_eq!(header.block_range(2), 0x2000..0x2800);
+        assert_eq!(blocks[0], header.first_block());
+        assert_eq!(blocks[0].offset, 0x40);
+        assert_eq!(blocks[2].offset, 0x70);
+        assert_eq!(header.get_length(), 0x78);
+        let mut bytes = vec![];
+        header.write_blocks_to(&blocks, &mut bytes).unwrap();
+        assert_eq!(bytes.len(), DataHeader::header_area_size(3));
+        let parsed = DataHeader::from_le_bytes(bytes[..DataHeader::SIZE].try_into().unwrap()).unwrap();
+        assert_eq!(parsed.blocks_from_le_bytes(&bytes[0x20..]), blocks);
+        // a single block is laid out the same as a plain data header
+        let (header, blocks) = DataHeader::new_blocks(CompressionType::LZ4, 0x100, 0, &[0x10]).unwrap();
+        let mut bytes = vec![];
+        header.write_blocks_to(&blocks, &mut bytes).unwrap();
+        assert_eq!(bytes, DataHeader::new(0x10, CompressionType::LZ4, 0x100).to_le_bytes());
+        assert!(matches!(DataHeader::new_blocks(CompressionType::LZ4, 0, 0, &[]),
+            Err(WriterError::InvalidBlockCount(0))));
+    }
+}
\ No newline at end of file
@@ -1,16 +1,26 @@
-use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::{Debug, Display, Formatter};
 use std::fs::File;
-use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::Path;
-use crate::serial::CompressionType;
+use std::io::{BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::read::{ApkReader, RawEntry, ReaderError};
+use crate::serial::{CompressionType, DataHeader, FileHeader, Header};
 
 #[derive(Debug)]
 pub enum WriterError {
     FilePathMissing(String),
     FileNameMissing,
-    FileAlreadyExists(String)
+    FileAlreadyExists(String),
+    NameTooLong { name: String, len: usize },
+    InvalidName(String),
+    EntryTooLarge { name: String, size: usize },
+    InvalidBlockCount(usize),
+    ArchiveTooLarge { name: String, offset: usize },
+    NoAllowedCompression { name: String },
+    FileNotFound(String),
+    IndexOutOfBounds(usize),
+    AlreadySaved
 }
 
 impl Error for WriterError {}
@@ -20,155 +30,1121 @@ impl Display for WriterError {
     }
 }
 
+/// Names are stored in a fixed size, NUL terminated field and are looked up by the game as a
+/// file name rather than a path. Names that pass can also be joined onto a folder without leaving
+/// it, which makes this useful for checking names read from an untrusted archive.
+pub fn validate_name(name: &str) -> Result<(), WriterError> {
+    if name.len() > FileHeader::MAX_NAME_LEN {
+        return Err(WriterError::NameTooLong { name: name.to_string(), len: name.len() });
+    }
+    if name.is_empty() || name == "." || name == ".." || name.contains(['\0', '/', '\\', ':']) {
+        return Err(WriterError::InvalidName(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Sizes and offsets are stored as `u32`, so anything past 4 GiB can't be represented.
+fn check_sizes(name: &str, offset: usize, dcmp_size: usize, cmp_pad_size: usize) -> Result<(), WriterError> {
+    let limit = u32::MAX as usize;
+    if dcmp_size > limit || cmp_pad_size > limit - DataHeader::SIZE {
+        return Err(WriterError::EntryTooLarge { name: name.to_string(), size: dcmp_size.max(cmp_pad_size) });
+    }
+    if offset > limit {
+        return Err(WriterError::ArchiveTooLarge { name: name.to_string(), offset });
+    }
+    Ok(())
+}
+
+/// Codec and level used to compress an entry.
+///
+/// Levels are passed to the codec: 0 to 9 for zlib and up to 22 for zstd, with values out of
+/// range clamped. For LZ4, levels above 0 use the high compression mode (up to 12) when the `lz4`
+/// backend is enabled. `lz4_flex` has no high compression mode, so the level is ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionOptions {
+    pub kind: CompressionType,
+    pub level: i32
+}
+
+impl CompressionOptions {
+    /// Default level for each codec, which is what entries were always compressed with.
+    pub fn new(kind: CompressionType) -> Self {
+        let level = match kind {
+            CompressionType::ZLib => flate2::Compression::fast().level() as i32,
+            CompressionType::LZ4 => 0,
+            CompressionType::ZStandard => zstd::DEFAULT_COMPRESSION_LEVEL
+        };
+        Self { kind, level }
+    }
+
+    pub fn with_level(kind: CompressionType, level: i32) -> Self {
+        Self { kind, level }
+    }
+
+    pub fn fastest(kind: CompressionType) -> Self {
+        let level = match kind {
+            CompressionType::ZLib => flate2::Compression::fast().level() as i32,
+            CompressionType::LZ4 => 0,
+            CompressionType::ZStandard => 1
+        };
+        Self { kind, level }
+    }
+
+    pub fn best(kind: CompressionType) -> Self {
+        let level = match kind {
+            CompressionType::ZLib => flate2::Compression::best().level() as i32,
+            CompressionType::LZ4 => 12,
+            CompressionType::ZStandard => *zstd::compression_level_range().end()
+        };
+        Self { kind, level }
+    }
+}
+
+impl Default for CompressionOptions {
+    fn default() -> Self {
+        Self::new(CompressionType::LZ4)
+    }
+}
+
+impl From<CompressionType> for CompressionOptions {
+    fn from(kind: CompressionType) -> Self {
+        Self::new(kind)
+    }
+}
+
+/// How entries that weren't given their own compression are compressed.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum CompressionPolicy {
+    /// Use the writer's default compression
+    #[default]
+    Default,
+    /// Compress with each of `allowed` and keep whichever output is smallest. Ties go to the
+    /// earliest in the list.
+    Smallest { allowed: Vec<CompressionOptions> }
+}
+
+impl CompressionPolicy {
+    /// Try each codec at its default level.
+    pub fn smallest(kinds: &[CompressionType]) -> Self {
+        Self::Smallest { allowed: kinds.iter().map(|k| CompressionOptions::new(*k)).collect() }
+    }
+}
+
+/// Where an entry ended up after [`ApkWriter::save`], and how it was compressed.
+#[derive(Debug, Clone)]
+pub struct SavedEntry {
+    pub name: String,
+    pub compression: CompressionType,
+    /// Space taken by the entry in the archive, including the data header and padding
+    pub stored_size: usize,
+    pub decompressed_size: usize,
+    /// Whether the entry was copied without being compressed again
+    pub raw: bool
+}
+
+pub(crate) fn compress(options: CompressionOptions, file: &[u8]) -> std::io::Result<Vec<u8>> {
+    Ok(match options.kind {
+        CompressionType::LZ4 => {
+            #[cfg(feature = "use-lz4-flex")]
+            {
+                let max_possible_size = (lz4_flex::block::get_maximum_output_size(file.len()) + 0xf) & !0xf;
+                let mut compressed = vec![0; max_possible_size];
+                let cmp_real_size = lz4_flex::block::compress_into(file, &mut compressed)
+                    .map_err(std::io::Error::other)?;
+                compressed.truncate(cmp_real_size);
+                compressed
+            }
+            #[cfg(feature = "use-lz4")]
+            {
+                let max_possible_size = unsafe { lz4::liblz4::LZ4F_compressBound(file.len(), std::ptr::null()) as usize & (isize::MAX as usize) };
+                let mut compressed = vec![0; max_possible_size];
+                let mode = match options.level {
+                    l if l > 0 => Some(lz4::block::CompressionMode::HIGHCOMPRESSION(l.min(12))),
+                    _ => None
+                };
+                let cmp_real_size = lz4::block::compress_to_buffer(file, mode, false, &mut compressed)?;
+                compressed.truncate(cmp_real_size);
+                compressed
+            }
+        },
+        _ => {
+            let mut compressed = vec![];
+            compress_stream(options, &mut &file[..], &mut compressed)?;
+            compressed
+        }
+    })
+}
+
+/// Copy `reader` into `writer` in chunks of the same size no matter how `reader` splits up its
+/// reads, so that compressing from a stream gives the same output as compressing from memory.
+fn copy_chunked<R: Read + ?Sized, W: Write>(reader: &mut R, writer: &mut W) -> std::io::Result<u64> {
+    let mut buffer = vec![0; 0x20000];
+    let mut total = 0;
+    loop {
+        let mut filled = 0;
+        while filled < buffer.len() {
+            match reader.read(&mut buffer[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e)
+            }
+        }
+        writer.write_all(&buffer[..filled])?;
+        total += filled as u64;
+        if filled < buffer.len() {
+            return Ok(total);
+        }
+    }
+}
+
+/// Compress everything read from `reader` straight into `writer`, returning the number of bytes
+/// read. Only zlib and zstd can be streamed, since LZ4 is stored as a single raw block.
+fn compress_stream<R: Read + ?Sized, W: Write>(options: CompressionOptions, reader: &mut R, writer: W)
+    -> std::io::Result<u64> {
+    match options.kind {
+        CompressionType::ZLib => {
+            let level = flate2::Compression::new(options.level.clamp(0, 9) as u32);
+            let mut encoder = flate2::write::ZlibEncoder::new(writer, level);
+            let read = copy_chunked(reader, &mut encoder)?;
+            encoder.finish()?;
+            Ok(read)
+        },
+        CompressionType::ZStandard => {
+            let range = zstd::compression_level_range();
+            let mut encoder = zstd::stream::Encoder::new(writer, options.level.clamp(*range.start(), *range.end()))?;
+            let read = copy_chunked(reader, &mut encoder)?;
+            encoder.finish()?;
+            Ok(read)
+        },
+        CompressionType::LZ4 => Err(std::io::Error::from(std::io::ErrorKind::Unsupported))
+    }
+}
+
+/// Compression that was chosen for an entry, along with its compressed contents.
+type Compressed = (CompressionOptions, Vec<u8>);
+
+/// An entry that's been read into memory and is waiting to be compressed.
+struct CompressJob {
+    file: Vec<u8>,
+    candidates: Vec<CompressionOptions>
+}
+
+impl CompressJob {
+    /// Compress with every candidate, keeping whichever output is smallest.
+    fn run(&self) -> std::io::Result<Compressed> {
+        let mut best: Option<Compressed> = None;
+        for compression in &self.candidates {
+            let compressed = compress(*compression, &self.file)?;
+            if best.as_ref().is_none_or(|(_, b)| compressed.len() < b.len()) {
+                best = Some((*compression, compressed));
+            }
+        }
+        Ok(best.unwrap())
+    }
+}
+
+/// How an entry is written by [`ApkWriter::save`].
+enum Job {
+    Raw,
+    Buffered(CompressJob),
+    // compressed straight from the entry's stream into the archive
+    Streamed(CompressionOptions)
+}
+
+fn run_job(job: &Job) -> std::io::Result<Option<Compressed>> {
+    match job {
+        Job::Buffered(job) => job.run().map(Some),
+        _ => Ok(None)
+    }
+}
+
+type OpenReader<'a> = Box<dyn FnOnce() -> std::io::Result<Box<dyn Read + 'a>> + 'a>;
+
+/// Reader that's only opened the first time it's read from, so that files aren't opened and
+/// buffers aren't generated until the entry is compressed.
+struct LazyReader<'a> {
+    open: Option<OpenReader<'a>>,
+    reader: Option<Box<dyn Read + 'a>>
+}
+
+impl<'a> LazyReader<'a> {
+    fn new(open: impl FnOnce() -> std::io::Result<Box<dyn Read + 'a>> + 'a) -> Self {
+        Self { open: Some(Box::new(open)), reader: None }
+    }
+}
+
+impl Read for LazyReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if let Some(open) = self.open.take() {
+            self.reader = Some(open()?);
+        }
+        match &mut self.reader {
+            Some(reader) => reader.read(buf),
+            None => Err(std::io::Error::other("entry failed to open"))
+        }
+    }
+}
+
+enum EntrySource<'a> {
+    Stream(Box<dyn Read + 'a>),
+    // copied verbatim from another archive
+    Raw(Box<RawEntry>)
+}
+
 pub struct ApkWriterEntry<'a> {
-    index: usize,
-    compression_type: CompressionType,
-    data: Box<dyn Read + 'a>
+    // falls back to the writer's default when not set
+    compression: Option<CompressionOptions>,
+    source: EntrySource<'a>,
+    file_unk: [u32; 5],
+    file_unk2: u32,
+    data_unk: [u32; 3]
 }
 
 impl<'a> ApkWriterEntry<'a> {
-    pub fn new(index: usize, compression_type: CompressionType, data: Box<dyn Read + 'a>) -> Self {
-        Self { index, compression_type, data }
+    pub fn new(compression: impl Into<CompressionOptions>, data: Box<dyn Read + 'a>) -> Self {
+        Self { compression: Some(compression.into()), source: EntrySource::Stream(data), file_unk: [0; 5], file_unk2: 0, data_unk: [0; 3] }
+    }
+
+    /// Entry that's copied into the archive as is, keeping its data header.
+    pub fn raw(raw: RawEntry) -> Self {
+        Self {
+            compression: None,
+            data_unk: raw.data_header.get_unk(),
+            source: EntrySource::Raw(Box::new(raw)),
+            file_unk: [0; 5],
+            file_unk2: 0
+        }
+    }
+
+    pub fn set_compression(&mut self, compression: Option<CompressionOptions>) {
+        self.compression = compression;
+    }
+
+    /// Values written to the unknown fields of this entry's [`FileHeader`]
+    pub fn set_file_header_unk(&mut self, unk: [u32; 5], unk2: u32) {
+        self.file_unk = unk;
+        self.file_unk2 = unk2;
+    }
+
+    /// Values written to the unknown fields of this entry's [`DataHeader`]. Raw entries keep the
+    /// values already in their data header.
+    pub fn set_data_header_unk(&mut self, unk: [u32; 3]) {
+        self.data_unk = unk;
+    }
+}
+
+/// A file that's written to a temporary file next to `path`, and only replaces `path` once
+/// [`AtomicFile::commit`] is called. If it's dropped before then, the temporary file is removed and
+/// anything already at `path` is left untouched.
+pub struct AtomicFile {
+    file: BufWriter<File>,
+    temp: PathBuf,
+    path: PathBuf,
+    committed: bool
+}
+
+impl AtomicFile {
+    pub fn create<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = path.as_ref().to_path_buf();
+        let name = path.file_name().ok_or(std::io::ErrorKind::InvalidInput)?.to_string_lossy();
+        let temp = path.with_file_name(format!(".{}.{}.{}.tmp", name, std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)));
+        let file = BufWriter::new(File::options().read(true).write(true).create_new(true).open(&temp)?);
+        Ok(Self { file, temp, path, committed: false })
+    }
+
+    /// Flush everything to disk, then move the temporary file over the destination. If the
+    /// destination already exists, its permissions are carried over to the new file.
+    pub fn commit(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        if !self.committed {
+            match std::fs::metadata(&self.path) {
+                Ok(meta) => self.file.get_ref().set_permissions(meta.permissions())?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e)
+            }
+        }
+        self.file.get_ref().sync_all()?;
+        if !self.committed {
+            std::fs::rename(&self.temp, &self.path)?;
+            self.committed = true;
+            // make sure the rename itself survives a crash
+            #[cfg(unix)]
+            if let Some(parent) = self.path.parent().filter(|p| !p.as_os_str().is_empty()) {
+                File::open(parent)?.sync_all()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for AtomicFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = std::fs::remove_file(&self.temp);
+        }
     }
 }
 
 pub struct ApkWriter<'a, S: Write + Seek> {
     owner: S,
-    // preserve order that files were inserted into APK in
-    files: HashMap<String, ApkWriterEntry<'a>>
+    header: Header,
+    // entries in the order they're written to the archive
+    files: Vec<(String, ApkWriterEntry<'a>)>,
+    compression: CompressionOptions,
+    policy: CompressionPolicy,
+    exclude_zstd: bool,
+    // entries are read from their sources when saving, so they can only be saved once
+    saved: bool,
+    // called once everything has been written, to move a file backed archive into place
+    commit: Option<fn(&mut S) -> std::io::Result<()>>
 }
 
-impl ApkWriter<'_, BufWriter<File>> {
+impl ApkWriter<'_, AtomicFile> {
+    /// Write an archive to `path`. Nothing at `path` is touched until [`ApkWriter::save`]
+    /// succeeds, so an existing archive survives a failed save.
     pub fn setup<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
-        let owner = BufWriter::new(File::create(path)?);
-        let files = HashMap::new();
-        Ok(Self {
-            owner, files
-        })
+        let mut writer = Self::new(AtomicFile::create(path)?);
+        writer.commit = Some(AtomicFile::commit);
+        Ok(writer)
     }
 }
 
 impl<'a, S: Write + Seek> ApkWriter<'a, S> {
+    /// Write an archive into any seekable sink, such as a `Cursor` over an in-memory buffer.
+    pub fn new(owner: S) -> Self {
+        Self {
+            owner, header: Header::new(0), files: vec![], compression: CompressionOptions::default(),
+            policy: CompressionPolicy::default(), exclude_zstd: false, saved: false, commit: None
+        }
+    }
+
+    /// Return the sink, along with everything written to it by [`ApkWriter::save`].
+    pub fn into_inner(self) -> S {
+        self.owner
+    }
+
     pub fn add_external_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Box<dyn Error>> {
-        self.add_external_file_with_compression(CompressionType::LZ4, path)
+        self.add_external_file_inner(None, path)
     }
 
     pub fn add_external_file_with_compression<P: AsRef<Path>>(&mut self,
-        cmp_type: CompressionType, path: P) -> Result<(), Box<dyn Error>> {
+        compression: impl Into<CompressionOptions>, path: P) -> Result<(), Box<dyn Error>> {
+        self.add_external_file_inner(Some(compression.into()), path)
+    }
+
+    fn add_external_file_inner<P: AsRef<Path>>(&mut self,
+        compression: Option<CompressionOptions>, path: P) -> Result<(), Box<dyn Error>> {
         if !std::fs::exists(&path)? {
             return Err(Box::new(WriterError::FilePathMissing(path.as_ref().to_str().unwrap().to_string())));
         }
-        let name = path.as_ref().file_name().ok_or(WriterError::FileNameMissing)?
-            .to_str().unwrap().to_string();
-        if self.files.contains_key(&name) {
-            return Err(Box::new(WriterError::FileAlreadyExists(name)));
-        }
-        let stream = File::open(path)?;
-        self.files.insert(name, ApkWriterEntry::new(self.files.len(), cmp_type, Box::new(stream)));
+        let name = path.as_ref().file_name().ok_or(WriterError::FileNameMissing)?;
+        let name = name.to_str().ok_or_else(|| WriterError::InvalidName(name.to_string_lossy().into_owned()))?
+            .to_string();
+        self.check_new_name(&name)?;
+        let path = path.as_ref().to_path_buf();
+        let stream = LazyReader::new(move || Ok(Box::new(File::open(path)?)));
+        self.push(name, compression, Box::new(stream));
         Ok(())
     }
 
     pub fn add_internal_file(&mut self, name: &str, stream: &'a [u8]) -> Result<(), Box<dyn Error>> {
-        self.add_internal_file_with_compression(name, CompressionType::LZ4, stream)
+        self.add_internal_file_inner(name, None, stream)
     }
 
     pub fn add_internal_file_with_compression(&mut self, name: &str,
-        cmp_type: CompressionType, stream: &'a [u8]) -> Result<(), Box<dyn Error>> {
-        let name = name.to_string();
-        if self.files.contains_key(&name) {
-            return Err(Box::new(WriterError::FileAlreadyExists(name)));
+        compression: impl Into<CompressionOptions>, stream: &'a [u8]) -> Result<(), Box<dyn Error>> {
+        self.add_internal_file_inner(name, Some(compression.into()), stream)
+    }
+
+    fn add_internal_file_inner(&mut self, name: &str,
+        compression: Option<CompressionOptions>, stream: &'a [u8]) -> Result<(), Box<dyn Error>> {
+        self.check_new_name(name)?;
+        self.push(name.to_string(), compression, Box::new(stream));
+        Ok(())
+    }
+
+    /// Add an entry that owns its contents.
+    pub fn add_owned(&mut self, name: &str, data: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        self.add_reader(name, Cursor::new(data))
+    }
+
+    /// Add an entry that's read from `reader` when the archive is saved.
+    pub fn add_reader(&mut self, name: &str, reader: impl Read + 'a) -> Result<(), Box<dyn Error>> {
+        self.check_new_name(name)?;
+        self.push(name.to_string(), None, Box::new(reader));
+        Ok(())
+    }
+
+    /// Add an entry whose contents are only generated by `data` when the archive is saved.
+    pub fn add_with(&mut self, name: &str, data: impl FnOnce() -> std::io::Result<Vec<u8>> + 'a)
+        -> Result<(), Box<dyn Error>> {
+        self.add_reader(name, LazyReader::new(move || Ok(Box::new(Cursor::new(data()?)))))
+    }
+
+    fn push(&mut self, name: String, compression: Option<CompressionOptions>, data: Box<dyn Read + 'a>) {
+        let mut entry = ApkWriterEntry::new(CompressionOptions::default(), data);
+        entry.set_compression(compression);
+        self.files.push((name, entry));
+    }
+
+    /// Add an entry without checking its name, for callers that have already checked it or that
+    /// keep the duplicate names of an existing archive.
+    pub(crate) fn push_entry(&mut self, name: String, entry: ApkWriterEntry<'a>) {
+        self.files.push((name, entry));
+    }
+
+    fn check_new_name(&self, name: &str) -> Result<(), WriterError> {
+        validate_name(name)?;
+        if self.contains(name) {
+            return Err(WriterError::FileAlreadyExists(name.to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Entry names in the order they'll be saved.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.files.iter().map(|(name, _)| name.as_str())
+    }
+
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        self.files.iter().position(|(n, _)| n == name)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.index_of(name).is_some()
+    }
+
+    fn find(&self, name: &str) -> Result<usize, WriterError> {
+        self.index_of(name).ok_or_else(|| WriterError::FileNotFound(name.to_string()))
+    }
+
+    /// Add an entry at `index` in the archive, moving every entry after it along by one.
+    pub fn insert_at(&mut self, index: usize, name: &str, entry: ApkWriterEntry<'a>) -> Result<(), Box<dyn Error>> {
+        if index > self.files.len() {
+            return Err(Box::new(WriterError::IndexOutOfBounds(index)));
+        }
+        self.check_new_name(name)?;
+        self.files.insert(index, (name.to_string(), entry));
+        Ok(())
+    }
+
+    /// Move an entry to `index`, keeping the order of every other entry.
+    pub fn move_to(&mut self, name: &str, index: usize) -> Result<(), WriterError> {
+        let from = self.find(name)?;
+        if index >= self.files.len() {
+            return Err(WriterError::IndexOutOfBounds(index));
         }
-        self.files.insert(name, ApkWriterEntry::new(self.files.len(), cmp_type, Box::new(stream)));
+        let entry = self.files.remove(from);
+        self.files.insert(index, entry);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<ApkWriterEntry<'a>> {
+        let index = self.index_of(name)?;
+        Some(self.files.remove(index).1)
+    }
+
+    /// Sort entries by name, byte by byte.
+    pub fn sort_by_name(&mut self) {
+        self.files.sort_by(|(a, _), (b, _)| a.cmp(b));
+    }
+
+    /// Compression used by entries that weren't given their own. Defaults to LZ4.
+    pub fn set_default_compression(&mut self, compression: impl Into<CompressionOptions>) {
+        self.compression = compression.into();
+    }
+
+    pub fn set_compression_policy(&mut self, policy: CompressionPolicy) {
+        self.policy = policy;
+    }
+
+    /// Refuse to write ZStandard entries, for mods that can't depend on OpenGFD to load them.
+    /// [`CompressionPolicy::Smallest`] skips ZStandard, and saving fails for any entry that can
+    /// only be written with it.
+    pub fn set_exclude_zstd(&mut self, value: bool) {
+        self.exclude_zstd = value;
+    }
+
+    /// Header written by [`ApkWriter::save`]. The entry count is filled in when saving.
+    pub fn header_mut(&mut self) -> &mut Header {
+        &mut self.header
+    }
+
+    pub fn get_entry_mut(&mut self, name: &str) -> Option<&mut ApkWriterEntry<'a>> {
+        let index = self.index_of(name)?;
+        Some(&mut self.files[index].1)
+    }
+
+    /// Add an entry that's already compressed, such as one from [`ApkReader::get_raw_entry`].
+    pub fn add_raw_entry(&mut self, name: &str, raw: RawEntry) -> Result<(), Box<dyn Error>> {
+        self.check_new_name(name)?;
+        self.files.push((name.to_string(), ApkWriterEntry::raw(raw)));
         Ok(())
     }
 
+    /// Copy an entry from another archive without decompressing it, keeping its original headers.
+    /// Repacking every entry of an archive this way, along with its [`Header`], reproduces the
+    /// original file.
+    pub fn add_raw_entry_from<R: Read + Seek>(&mut self, reader: &mut ApkReader<R>, name: &str)
+        -> Result<(), Box<dyn Error>> {
+        let index = reader.index_of(name).ok_or_else(|| ReaderError::FileNotFound(name.to_string()))?;
+        let raw = reader.get_raw_entry_by_index(index)?;
+        self.add_raw_entry(name, raw)?;
+        let file_header = &reader.files[index];
+        self.files.last_mut().unwrap().1.set_file_header_unk(file_header.get_unk(), file_header.get_unk2());
+        Ok(())
+    }
+
+    /// Same as [`ApkWriter::remove`].
     pub fn remove_file(&mut self, name: &str) -> Option<ApkWriterEntry<'a>> {
-        self.files.remove(name)
+        self.remove(name)
+    }
+
+    /// Compression to try for an entry, leaving out anything excluded.
+    fn candidates(&self, name: &str, entry: &ApkWriterEntry) -> Result<Vec<CompressionOptions>, WriterError> {
+        let candidates: Vec<CompressionOptions> = match (entry.compression, &self.policy) {
+            (Some(compression), _) => vec![compression],
+            (None, CompressionPolicy::Default) => vec![self.compression],
+            (None, CompressionPolicy::Smallest { allowed }) => allowed.clone()
+        };
+        let candidates: Vec<CompressionOptions> = candidates.into_iter()
+            .filter(|c| !(self.exclude_zstd && c.kind == CompressionType::ZStandard)).collect();
+        match candidates.is_empty() {
+            true => Err(WriterError::NoAllowedCompression { name: name.to_string() }),
+            false => Ok(candidates)
+        }
+    }
+
+    /// Write the archive, returning the compression chosen for each entry in archive order.
+    ///
+    /// The archive starts at the sink's current position, and the sink is left just past the end
+    /// of it. Entries are used up as they're written, so saving a second time fails with
+    /// [`WriterError::AlreadySaved`].
+    ///
+    /// Entries compressed with zlib or zstd are streamed into the archive without being read into
+    /// memory first, unless they have more than one codec to choose from.
+    pub fn save(&mut self) -> Result<Vec<SavedEntry>, Box<dyn Error>> {
+        self.save_batched(1, true, |jobs| jobs.iter().map(run_job).collect())
+    }
+
+    /// Same as [`ApkWriter::save`], but compresses entries on every core. Entries are still read
+    /// and written one at a time in archive order, so the output is identical.
+    #[cfg(feature = "rayon")]
+    pub fn save_parallel(&mut self) -> Result<Vec<SavedEntry>, Box<dyn Error>> {
+        use rayon::prelude::*;
+        self.save_batched(rayon::current_num_threads(), false, |jobs| jobs.par_iter().map(run_job).collect())
     }
 
-    pub fn save(&mut self) -> Result<(), Box<dyn Error>> {
-        self.owner.write(crate::serial::Header::new(self.files.len()).to_bytes())?;
+    /// Read `batch_size` entries at a time, compress them with `compress_batch` then write them.
+    /// With `stream` set, entries that can be streamed skip the batch and are compressed as they're
+    /// written.
+    fn save_batched<F>(&mut self, batch_size: usize, stream: bool, compress_batch: F) -> Result<Vec<SavedEntry>, Box<dyn Error>>
+    where F: Fn(&[Job]) -> Vec<std::io::Result<Option<Compressed>>> {
+        if self.saved {
+            return Err(Box::new(WriterError::AlreadySaved));
+        }
+        // check that every entry can be written before anything is
+        let mut candidates = self.files.iter().map(|(name, entry)| match &entry.source {
+            EntrySource::Raw(raw) => match self.exclude_zstd && raw.data_header.get_compress_type() == CompressionType::ZStandard {
+                true => Err(WriterError::NoAllowedCompression { name: name.to_string() }),
+                false => Ok(vec![])
+            },
+            EntrySource::Stream(_) => self.candidates(name, entry)
+        }).collect::<Result<Vec<_>, _>>()?.into_iter();
+
+        self.saved = true;
+        // offsets in the archive are relative to wherever it starts in the sink
+        let base = self.owner.stream_position()?;
+        let mut saved = Vec::with_capacity(self.files.len());
+        let mut header = self.header.clone();
+        header.count = self.files.len() as u32;
+        header.write_to(&mut self.owner)?;
         let blank = [0u8; 0x100];
-        let mut pointer = (self.files.len() * size_of::<crate::serial::FileHeader>())
-            + size_of::<crate::serial::Header>();
-        let mut files = Vec::with_capacity(self.files.len());
-        (0..self.files.len()).for_each(|_| files.push(None));
-        for (name, entry) in &mut self.files {
-            let index = entry.index;
-            files[index] = Some((name, entry));
-        }
-        for (i, (name, entry)) in files.iter_mut()
-            .filter_map(|e| e.as_mut()).enumerate() {
+        let mut pointer = (self.files.len() * FileHeader::SIZE)
+            + Header::SIZE;
+        for (batch_index, batch) in self.files.chunks_mut(batch_size.max(1)).enumerate() {
             // get file contents
-            let mut file = vec![];
-            entry.data.read_to_end(&mut file)?;
-            // compress file
-            let (cmp_real_size, cmp_pad_size, compressed) = match entry.compression_type {
-                CompressionType::ZLib => {
-                    let mut compressed = vec![];
-                    let cmp_real_size = {
-                        let mut encoder = flate2::write::ZlibEncoder::new(&mut compressed, flate2::Compression::fast());
-                        encoder.write_all(&file)?;
-                        encoder.finish()?.len()
-                    };
-                    let cmp_pad_size = (cmp_real_size + 0xf) & !0xf; // align to nearest 0x10
-                    (cmp_real_size, cmp_pad_size, compressed)
-                },
-                CompressionType::LZ4 => {
-                    #[cfg(feature = "use-lz4-flex")]
-                    {
-                        let max_possible_size = (lz4_flex::block::get_maximum_output_size(file.len()) + 0xf) & !0xf;
-                        let mut compressed = Vec::with_capacity(max_possible_size);
-                        unsafe { compressed.set_len(compressed.capacity()) };
-                        let cmp_real_size = lz4_flex::block::compress_into(&file, &mut compressed)?;
-                        unsafe { compressed.set_len(cmp_real_size) };
-                        let cmp_pad_size = (cmp_real_size + 0xf) & !0xf; // align to nearest 0x10
-                        (cmp_real_size, cmp_pad_size, compressed)
-                    }
-                    #[cfg(feature = "use-lz4")]
-                    {
-                        let max_possible_size = unsafe { lz4::liblz4::LZ4F_compressBound(file.len(), std::ptr::null()) as usize & (isize::MAX as usize) };
-                        let mut compressed = Vec::with_capacity(max_possible_size);
-                        unsafe { compressed.set_len(compressed.capacity()) };
-                        let cmp_real_size = lz4::block::compress_to_buffer(&file, None, false, &mut compressed)?;
-                        unsafe { compressed.set_len(cmp_real_size) };
+            let mut jobs = Vec::with_capacity(batch.len());
+            for (_, entry) in batch.iter_mut() {
+                let candidates = candidates.next().unwrap();
+                let EntrySource::Stream(data) = &mut entry.source else {
+                    jobs.push(Job::Raw);
+                    continue;
+                };
+                if stream && candidates.len() == 1
+                    && candidates[0].kind != CompressionType::LZ4 {
+                    jobs.push(Job::Streamed(candidates[0]));
+                    continue;
+                }
+                let mut file = vec![];
+                data.read_to_end(&mut file)?;
+                jobs.push(Job::Buffered(CompressJob { file, candidates }));
+            }
+            let compressed = compress_batch(&jobs);
+            for (i, ((name, entry), (job, compressed))) in batch.iter_mut()
+                .zip(jobs.iter().zip(compressed)).enumerate() {
+                let file_header_offset = Header::SIZE + (batch_index * batch_size.max(1) + i) * FileHeader::SIZE;
+                self.owner.seek(SeekFrom::Start(base + file_header_offset as u64))?;
+                let (job, (compression, compressed)) = match (job, &mut entry.source) {
+                    (Job::Buffered(job), _) => (job, compressed?.unwrap()),
+                    (Job::Streamed(compression), EntrySource::Stream(data)) => {
+                        // compress past the data header, then go back and fill it in
+                        let data_start = base + (pointer + DataHeader::SIZE) as u64;
+                        self.owner.seek(SeekFrom::Start(data_start))?;
+                        let dcmp_size = compress_stream(*compression, data, &mut self.owner)? as usize;
+                        let cmp_real_size = (self.owner.stream_position()? - data_start) as usize;
                         let cmp_pad_size = (cmp_real_size + 0xf) & !0xf; // align to nearest 0x10
-                        (cmp_real_size, cmp_pad_size, compressed)
-                    }
-                },
-                CompressionType::ZStandard => {
-                    let compressed = zstd::encode_all(std::io::Cursor::new(&file), zstd::DEFAULT_COMPRESSION_LEVEL)?;
-                    let cmp_pad_size = (compressed.len() + 0xf) & !0xf; // align to nearest 0x10
-                    (compressed.len(), cmp_pad_size, compressed)
-                },
-            };
-            self.owner.write(crate::serial::FileHeader::new(name, cmp_pad_size, pointer).to_bytes())?;
-            self.owner.seek(SeekFrom::Start(pointer as u64))?;
-            self.owner.write(crate::serial::DataHeader::new(cmp_real_size,
-                entry.compression_type, file.len()).to_bytes())?;
-            self.owner.write(&compressed)?;
-            if cmp_real_size % 0x10 != 0 { // fill padding with zeroes
-                self.owner.write(&blank[..0x10 - (cmp_real_size % 0x10)])?;
+                        check_sizes(name, pointer, dcmp_size, cmp_pad_size)?;
+                        self.owner.write_all(&blank[..cmp_pad_size - cmp_real_size])?;
+                        self.owner.seek(SeekFrom::Start(base + pointer as u64))?;
+                        let mut data_header = DataHeader::new(cmp_real_size, compression.kind, dcmp_size);
+                        data_header.set_unk(entry.data_unk);
+                        self.owner.write_all(&data_header.to_le_bytes())?;
+                        self.owner.seek(SeekFrom::Start(base + file_header_offset as u64))?;
+                        let mut file_header = FileHeader::new(name, cmp_pad_size, pointer)?;
+                        file_header.set_unk(entry.file_unk);
+                        file_header.set_unk2(entry.file_unk2);
+                        file_header.write_to(&mut self.owner)?;
+                        pointer += cmp_pad_size + DataHeader::SIZE;
+                        saved.push(SavedEntry {
+                            name: name.to_string(),
+                            compression: compression.kind,
+                            stored_size: cmp_pad_size + DataHeader::SIZE,
+                            decompressed_size: dcmp_size,
+                            raw: false
+                        });
+                        continue;
+                    },
+                    (Job::Raw, EntrySource::Raw(raw)) => {
+                        check_sizes(name, pointer, raw.data_header.get_decompressed_size() as usize,
+                            raw.data.len() - DataHeader::SIZE)?;
+                        let mut file_header = FileHeader::new(name, raw.data.len() - DataHeader::SIZE, pointer)?;
+                        file_header.set_unk(entry.file_unk);
+                        file_header.set_unk2(entry.file_unk2);
+                        file_header.write_to(&mut self.owner)?;
+                        self.owner.seek(SeekFrom::Start(base + pointer as u64))?;
+                        self.owner.write_all(&raw.data)?;
+                        pointer += raw.data.len();
+                        saved.push(SavedEntry {
+                            name: name.to_string(),
+                            compression: raw.data_header.get_compress_type(),
+                            stored_size: raw.data.len(),
+                            decompressed_size: raw.data_header.get_decompressed_size() as usize,
+                            raw: true
+                        });
+                        continue;
+                    },
+                    _ => unreachable!()
+                };
+                let cmp_real_size = compressed.len();
+                let cmp_pad_size = (cmp_real_size + 0xf) & !0xf; // align to nearest 0x10
+                check_sizes(name, pointer, job.file.len(), cmp_pad_size)?;
+                let mut file_header = FileHeader::new(name, cmp_pad_size, pointer)?;
+                file_header.set_unk(entry.file_unk);
+                file_header.set_unk2(entry.file_unk2);
+                file_header.write_to(&mut self.owner)?;
+                self.owner.seek(SeekFrom::Start(base + pointer as u64))?;
+                let mut data_header = DataHeader::new(cmp_real_size, compression.kind, job.file.len());
+                data_header.set_unk(entry.data_unk);
+                self.owner.write_all(&data_header.to_le_bytes())?;
+                self.owner.write_all(&compressed)?;
+                if !cmp_real_size.is_multiple_of(0x10) { // fill padding with zeroes
+                    self.owner.write_all(&blank[..0x10 - (cmp_real_size % 0x10)])?;
+                }
+                pointer += cmp_pad_size + DataHeader::SIZE;
+                saved.push(SavedEntry {
+                    name: name.to_string(),
+                    compression: compression.kind,
+                    stored_size: cmp_pad_size + DataHeader::SIZE,
+                    decompressed_size: job.file.len(),
+                    raw: false
+                });
             }
-            pointer += cmp_pad_size + size_of::<crate::serial::DataHeader>();
-            let next_file_header = size_of::<crate::serial::Header>()
-                + ((i + 1) * size_of::<crate::serial::FileHeader>());
-            self.owner.seek(SeekFrom::Start(next_file_header as u64))?;
         }
-        Ok(())
+        self.owner.seek(SeekFrom::Start(base + pointer as u64))?;
+        self.owner.flush()?;
+        if let Some(commit) = self.commit {
+            commit(&mut self.owner)?;
+        }
+        Ok(saved)
     }
 }
 
 #[cfg(test)]
 pub mod tests {
     use std::error::Error;
-    use crate::write::ApkWriter;
+    use std::io::{Cursor, Read, Write};
+    use crate::read::{ApkReader, RawEntry, ReaderError};
+    use crate::read::tests::build_blocks_apk;
+    use crate::serial::CompressionType;
+    use crate::write::{ApkWriterEntry, AtomicFile, check_sizes, compress, compress_stream, ApkWriter, CompressionOptions, CompressionPolicy, WriterError};
 
     #[test]
     fn test_write() -> Result<(), Box<dyn Error>> {
+        let path = "E:/Metaphor/base_cpk/COMMON/ui/ss/01_grandtrad.dds";
+        if !std::fs::exists(path)? {
+            return Ok(());
+        }
         let mut apk = ApkWriter::setup("E:/Metaphor/base_cpk/COMMON/ui/ss/01_grandtrad_out.apk")?;
-        apk.add_external_file("E:/Metaphor/base_cpk/COMMON/ui/ss/01_grandtrad.dds")?;
+        apk.add_external_file(path)?;
         apk.save()?;
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_invalid_names() {
+        let mut apk = ApkWriter::new(Cursor::new(vec![]));
+        let long = "a".repeat(0x100);
+        let err = apk.add_internal_file(&long, &[]).err().unwrap();
+        assert!(matches!(err.downcast_ref::<WriterError>(), Some(WriterError::NameTooLong { len: 0x100, .. })));
+        assert!(apk.add_internal_file(&long[..0xff], &[]).is_ok());
+        for name in ["", "a\0.dds", "a/b.dds", "a\\b.dds", "..", ".", "C:a.dds"] {
+            let err = apk.add_internal_file(name, &[]).err().unwrap();
+            assert!(matches!(err.downcast_ref::<WriterError>(), Some(WriterError::InvalidName(_))));
+        }
+    }
+
+    #[test]
+    fn test_size_overflow() {
+        let limit = u32::MAX as usize;
+        assert!(check_sizes("a.dds", limit, limit, limit - 0x30).is_ok());
+        assert!(matches!(check_sizes("a.dds", 0x130, limit + 1, 0x10),
+            Err(WriterError::EntryTooLarge { size, .. }) if size == limit + 1));
+        assert!(matches!(check_sizes("a.dds", 0x130, 0x10, limit - 0x20),
+            Err(WriterError::EntryTooLarge { .. })));
+        assert!(matches!(check_sizes("a.dds", limit + 1, 0x10, 0x10),
+            Err(WriterError::ArchiveTooLarge { .. })));
+    }
+
+    #[test]
+    fn test_write_at_offset() -> Result<(), Box<dyn Error>> {
+        let texture: Vec<u8> = (0..0x3000).map(|i| ((i * i) % 61) as u8).collect();
+        let mut sink = Cursor::new(vec![]);
+        sink.write_all(&[0xff; 0x10])?;
+        let mut apk = ApkWriter::new(sink);
+        apk.add_internal_file_with_compression("a.dds", CompressionType::ZLib, &texture)?;
+        apk.add_internal_file_with_compression("b.dds", CompressionType::LZ4, b"second")?;
+        apk.save()?;
+        let err = apk.save().err().unwrap();
+        assert!(matches!(err.downcast_ref::<WriterError>(), Some(WriterError::AlreadySaved)));
+        let mut sink = apk.into_inner();
+        assert_eq!(sink.position(), sink.get_ref().len() as u64);
+
+        // the archive reads the same from the slice it was written to, or from the whole buffer
+        // with the stream starting at the archive
+        let bytes = sink.get_ref().clone();
+        assert_eq!(ApkReader::from_bytes(&bytes[0x10..])?.get_file("a.dds")?, texture);
+        sink.set_position(0x10);
+        let mut apk = ApkReader::new(sink)?;
+        assert_eq!(apk.get_file("a.dds")?, texture);
+        assert_eq!(apk.get_file("b.dds")?, b"second");
+        Ok(())
+    }
+
+    #[test]
+    fn test_raw_round_trip() -> Result<(), Box<dyn Error>> {
+        let mut apk = ApkWriter::new(Cursor::new(vec![]));
+        apk.header_mut().set_reserve(0x1234);
+        apk.add_internal_file_with_compression("a.dds", CompressionType::ZLib, &[1; 0x100])?;
+        apk.add_internal_file_with_compression("b.dds", CompressionType::LZ4, b"second")?;
+        apk.get_entry_mut("a.dds").unwrap().set_file_header_unk([1, 2, 3, 4, 5], 6);
+        apk.get_entry_mut("b.dds").unwrap().set_data_header_unk([7, 8, 9]);
+        apk.save()?;
+        let original = apk.into_inner().into_inner();
+
+        let mut reader = ApkReader::new(Cursor::new(original.clone()))?;
+        assert_eq!(reader.header().get_reserve(), 0x1234);
+        assert_eq!(reader.file_header(0).unwrap().get_unk(), [1, 2, 3, 4, 5]);
+        assert_eq!(reader.file_header(0).unwrap().get_unk2(), 6);
+        let mut apk = ApkWriter::new(Cursor::new(vec![]));
+        *apk.header_mut() = reader.header().clone();
+        apk.add_raw_entry_from(&mut reader, "a.dds")?;
+        apk.add_raw_entry_from(&mut reader, "b.dds")?;
+        apk.save()?;
+        assert_eq!(apk.into_inner().into_inner(), original);
+        Ok(())
+    }
+
+    #[test]
+    fn test_raw_entry() -> Result<(), Box<dyn Error>> {
+        let texture: Vec<u8> = (0..0x2800).map(|i| (i % 251) as u8).collect();
+        let mut apk = ApkWriter::new(Cursor::new(vec![]));
+        apk.add_internal_file_with_compression("a.dds", CompressionType::ZStandard, &texture)?;
+        apk.save()?;
+        let mut reader = ApkReader::new(Cursor::new(apk.into_inner().into_inner()))?;
+        let raw = reader.get_raw_entry("a.dds")?;
+        assert_eq!(raw.blocks().len(), 1);
+        assert_eq!(raw.decompress()?, texture);
+
+        // rebuild the entry from its compressed block, then copy both into a new archive
+        let block = raw.compressed_block(0).unwrap();
+        let rebuilt = RawEntry::new(raw.data_header(), &[block])?;
+        assert_eq!(rebuilt.as_bytes(), raw.as_bytes());
+        assert!(RawEntry::new(raw.data_header(), &[block, block]).is_err());
+
+        // multi-block entries are copied as is, but can't be rebuilt
+        let mut reader = ApkReader::new(Cursor::new(build_blocks_apk("d.dds", CompressionType::LZ4, &texture, 0x1000)?))?;
+        let blocks_raw = reader.get_raw_entry("d.dds")?;
+        let blocks: Vec<&[u8]> = (0..3).map(|i| blocks_raw.compressed_block(i).unwrap()).collect();
+        let err = RawEntry::new(blocks_raw.data_header(), &blocks).err().unwrap();
+        assert!(matches!(err.downcast_ref::<ReaderError>(), Some(ReaderError::BlockCountMismatch { expected: 1, actual: 3 })));
+        let mut apk = ApkWriter::new(Cursor::new(vec![]));
+        apk.add_raw_entry("a.dds", raw)?;
+        apk.add_raw_entry("b.dds", rebuilt)?;
+        apk.add_internal_file("c.dds", b"new")?;
+        apk.add_raw_entry("d.dds", blocks_raw)?;
+        apk.save()?;
+        let mut reader = ApkReader::new(Cursor::new(apk.into_inner().into_inner()))?;
+        assert_eq!(reader.get_file("a.dds")?, texture);
+        assert_eq!(reader.get_file("b.dds")?, texture);
+        assert_eq!(reader.get_file("c.dds")?, b"new");
+        assert_eq!(reader.get_file("d.dds")?, texture);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compression_options() -> Result<(), Box<dyn Error>> {
+        let texture: Vec<u8> = (0..0x4000).map(|i| ((i * i) % 61) as u8).collect();
+        for kind in [CompressionType::ZLib, CompressionType::LZ4, CompressionType::ZStandard] {
+            let fastest = compress(CompressionOptions::fastest(kind), &texture)?;
+            let best = compress(CompressionOptions::best(kind), &texture)?;
+            assert!(best.len() <= fastest.len());
+        }
+        let mut apk = ApkWriter::new(Cursor::new(vec![]));
+        apk.set_default_compression(CompressionOptions::best(CompressionType::ZStandard));
+        apk.add_internal_file("a.dds", &texture)?;
+        apk.add_internal_file_with_compression("b.dds", CompressionOptions::with_level(CompressionType::ZLib, 100), &texture)?;
+        apk.save()?;
+        let mut apk = ApkReader::new(Cursor::new(apk.into_inner().into_inner()))?;
+        let entries = apk.entries().collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(entries[0].compression, CompressionType::ZStandard);
+        assert_eq!(entries[1].compression, CompressionType::ZLib);
+        assert_eq!(apk.get_file("a.dds")?, texture);
+        assert_eq!(apk.get_file("b.dds")?, texture);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compression_policy() -> Result<(), Box<dyn Error>> {
+        let texture: Vec<u8> = (0..0x4000).map(|i| ((i * i) % 61) as u8).collect();
+        let all = [CompressionType::ZLib, CompressionType::LZ4, CompressionType::ZStandard];
+        let smallest = all.iter().min_by_key(|k| compress(CompressionOptions::new(**k), &texture).unwrap().len()).unwrap();
+        let mut apk = ApkWriter::new(Cursor::new(vec![]));
+        apk.set_compression_policy(CompressionPolicy::smallest(&all));
+        apk.add_internal_file("a.dds", &texture)?;
+        apk.add_internal_file_with_compression("b.dds", CompressionType::LZ4, &texture)?;
+        let saved = apk.save()?;
+        assert_eq!(saved[0].compression, *smallest);
+        assert_eq!(saved[1].compression, CompressionType::LZ4);
+        let mut reader = ApkReader::new(Cursor::new(apk.into_inner().into_inner()))?;
+        assert_eq!(reader.get_file("a.dds")?, texture);
+
+        let mut apk = ApkWriter::new(Cursor::new(vec![]));
+        apk.set_compression_policy(CompressionPolicy::smallest(&all));
+        apk.set_exclude_zstd(true);
+        apk.add_internal_file("a.dds", &texture)?;
+        assert_ne!(apk.save()?[0].compression, CompressionType::ZStandard);
+
+        let mut apk = ApkWriter::new(Cursor::new(vec![]));
+        apk.set_exclude_zstd(true);
+        apk.add_internal_file_with_compression("a.dds", CompressionType::ZStandard, &texture)?;
+        let err = apk.save().err().unwrap();
+        assert!(matches!(err.downcast_ref::<WriterError>(), Some(WriterError::NoAllowedCompression { .. })));
+        Ok(())
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_save_parallel() -> Result<(), Box<dyn Error>> {
+        let textures: Vec<Vec<u8>> = (0..20).map(|t| (0..0x3000).map(|i| ((i * t) % 251) as u8).collect()).collect();
+        let names: Vec<String> = (0..textures.len()).map(|t| format!("{}.dds", t)).collect();
+        let mut outputs = vec![];
+        // entries that save streams are also identical when compressed in parallel
+        for parallel in [false, true] {
+            let mut apk = ApkWriter::new(Cursor::new(vec![]));
+            apk.set_compression_policy(CompressionPolicy::smallest(&[CompressionType::ZLib, CompressionType::LZ4]));
+            for (name, texture) in names.iter().zip(&textures) {
+                apk.add_internal_file(name, texture)?;
+            }
+            apk.add_internal_file_with_compression("zlib.dds", CompressionType::ZLib, &textures[5])?;
+            apk.add_internal_file_with_compression("zstd.dds", CompressionType::ZStandard, &textures[6])?;
+            apk.remove_file("3.dds");
+            match parallel {
+                true => apk.save_parallel()?,
+                false => apk.save()?
+            };
+            outputs.push(apk.into_inner().into_inner());
+        }
+        assert_eq!(outputs[0], outputs[1]);
+        let mut apk = ApkReader::new(Cursor::new(outputs.pop().unwrap()))?;
+        assert_eq!(apk.len(), 21);
+        assert_eq!(apk.get_file("19.dds")?, textures[19]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_streamed_compression() -> Result<(), Box<dyn Error>> {
+        let texture: Vec<u8> = (0..0x5000).map(|i| ((i * i) % 61) as u8).collect();
+        for kind in [CompressionType::ZLib, CompressionType::ZStandard] {
+            // short reads from the source don't change the output
+            let options = CompressionOptions::new(kind);
+            let mut streamed = vec![];
+            let mut reader = texture[..0x123].chain(&texture[0x123..]);
+            assert_eq!(compress_stream(options, &mut reader, &mut streamed)?, texture.len() as u64);
+            assert_eq!(streamed, compress(options, &texture)?);
+        }
+
+        let mut apk = ApkWriter::new(Cursor::new(vec![]));
+        apk.add_internal_file_with_compression("a.dds", CompressionType::ZLib, &texture)?;
+        apk.add_internal_file_with_compression("b.dds", CompressionType::ZStandard, b"small")?;
+        apk.add_internal_file("c.dds", &texture)?;
+        let saved = apk.save()?;
+        let mut apk = ApkReader::new(Cursor::new(apk.into_inner().into_inner()))?;
+        let entries = apk.entries().collect::<Result<Vec<_>, _>>()?;
+        for (saved, entry) in saved.iter().zip(&entries) {
+            assert_eq!(saved.stored_size, entry.stored_size as usize);
+            assert_eq!(saved.decompressed_size, entry.decompressed_size as usize);
+        }
+        assert_eq!(apk.get_file("a.dds")?, texture);
+        assert_eq!(apk.get_file("b.dds")?, b"small");
+        assert_eq!(apk.get_file("c.dds")?, texture);
+        Ok(())
+    }
+
+    #[test]
+    fn test_atomic_save() -> Result<(), Box<dyn Error>> {
+        struct Failing;
+        impl Read for Failing {
+            fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("read failed"))
+            }
+        }
+        let dir = std::env::temp_dir().join(format!("metaphor-apk-atomic-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("out.apk");
+        std::fs::write(&path, b"original")?;
+
+        let mut apk = ApkWriter::setup(&path)?;
+        apk.add_internal_file("a.dds", b"first")?;
+        apk.insert_at(1, "b.dds", ApkWriterEntry::new(CompressionType::LZ4, Box::new(Failing)))?;
+        assert!(apk.save().is_err());
+        drop(apk);
+        assert_eq!(std::fs::read(&path)?, b"original");
+        assert_eq!(std::fs::read_dir(&dir)?.count(), 1);
+
+        let mut apk = ApkWriter::setup(&path)?;
+        apk.add_internal_file("a.dds", b"first")?;
+        apk.save()?;
+        drop(apk);
+        assert_eq!(ApkReader::read(&path)?.get_file("a.dds")?, b"first");
+        assert_eq!(std::fs::read_dir(&dir)?.count(), 1);
+
+        // nothing is written unless the file is committed
+        let mut file = AtomicFile::create(&path)?;
+        file.write_all(b"discarded")?;
+        drop(file);
+        assert_eq!(ApkReader::read(&path)?.get_file("a.dds")?, b"first");
+
+        // replacing a file keeps its permissions
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640))?;
+            let mut apk = ApkWriter::setup(&path)?;
+            apk.add_internal_file("a.dds", b"second")?;
+            apk.save()?;
+            drop(apk);
+            assert_eq!(std::fs::metadata(&path)?.permissions().mode() & 0o777, 0o640);
+        }
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_entry_order() -> Result<(), Box<dyn Error>> {
+        let mut apk = ApkWriter::new(Cursor::new(vec![]));
+        for name in ["c.dds", "a.dds", "b.dds"] {
+            apk.add_internal_file(name, name.as_bytes())?;
+        }
+        // new entries go after everything that's left, whatever was removed
+        assert!(apk.remove("c.dds").is_some());
+        apk.add_internal_file("d.dds", b"d.dds")?;
+        assert!(apk.remove("a.dds").is_some());
+        assert!(apk.remove("a.dds").is_none());
+        apk.add_internal_file("e.dds", b"e.dds")?;
+        assert_eq!(apk.names().collect::<Vec<_>>(), ["b.dds", "d.dds", "e.dds"]);
+
+        apk.insert_at(0, "f.dds", ApkWriterEntry::new(CompressionType::ZLib, Box::new(&b"f.dds"[..])))?;
+        apk.move_to("b.dds", 3)?;
+        assert_eq!(apk.names().collect::<Vec<_>>(), ["f.dds", "d.dds", "e.dds", "b.dds"]);
+        assert!(matches!(apk.move_to("b.dds", 4), Err(WriterError::IndexOutOfBounds(4))));
+        assert!(matches!(apk.move_to("a.dds", 0), Err(WriterError::FileNotFound(_))));
+        let err = apk.insert_at(5, "g.dds", ApkWriterEntry::new(CompressionType::LZ4, Box::new(&b""[..]))).err().unwrap();
+        assert!(matches!(err.downcast_ref::<WriterError>(), Some(WriterError::IndexOutOfBounds(5))));
+        apk.save()?;
+        let mut reader = ApkReader::new(Cursor::new(apk.into_inner().into_inner()))?;
+        assert_eq!(reader.create_file_list()?, "f.dds\nd.dds\ne.dds\nb.dds\n");
+        assert_eq!(reader.get_file("f.dds")?, b"f.dds");
+
+        let mut apk = ApkWriter::new(Cursor::new(vec![]));
+        for name in ["c.dds", "a.dds", "b.dds"] {
+            apk.add_internal_file(name, name.as_bytes())?;
+        }
+        apk.sort_by_name();
+        assert_eq!(apk.names().collect::<Vec<_>>(), ["a.dds", "b.dds", "c.dds"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_owned_sources() -> Result<(), Box<dyn Error>> {
+        let generated = std::rc::Rc::new(std::cell::Cell::new(false));
+        let mut apk = ApkWriter::new(Cursor::new(vec![]));
+        apk.add_owned("a.dds", vec![1; 0x100])?;
+        apk.add_reader("b.dds", Cursor::new(b"reader".to_vec()))?;
+        let flag = generated.clone();
+        apk.add_with("c.dds", move || {
+            flag.set(true);
+            Ok(b"generated".to_vec())
+        })?;
+        assert!(apk.add_owned("a.dds", vec![]).is_err());
+        assert!(!generated.get());
+        apk.save()?;
+        assert!(generated.get());
+        let mut reader = ApkReader::new(Cursor::new(apk.into_inner().into_inner()))?;
+        assert_eq!(reader.get_file("a.dds")?, vec![1; 0x100]);
+        assert_eq!(reader.get_file("b.dds")?, b"reader");
+        assert_eq!(reader.get_file("c.dds")?, b"generated");
+
+        let mut apk = ApkWriter::new(Cursor::new(vec![]));
+        apk.add_with("a.dds", || Err(std::io::Error::other("generation failed")))?;
+        assert!(apk.save().is_err());
+        Ok(())
+    }
+}